@@ -0,0 +1,189 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
+use std::{env, fs, io, process};
+
+static CACHE_DIR_ENV: &str = "DD_TEST_OPTIMIZATION_CACHE_DIR";
+static CACHE_TTL_ENV: &str = "DD_TEST_OPTIMIZATION_CACHE_TTL";
+static DEFAULT_TTL: Duration = Duration::from_secs(60 * 60);
+
+/********************************
+    Cache key
+*********************************/
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CacheKey {
+    pub repository_url: String,
+    pub commit_sha: String,
+    pub service: String,
+    pub env: String,
+}
+
+impl CacheKey {
+    // Discovers the key using the same sources as the native library: DD_* variables first, then git
+    #[allow(dead_code)]
+    pub fn discover(working_directory: Option<&str>) -> Self {
+        let repository_url = env_value("DD_GIT_REPOSITORY_URL")
+            .or_else(|| git_value(working_directory, &["config", "--get", "remote.origin.url"]))
+            .unwrap_or_default();
+        let commit_sha = env_value("DD_GIT_COMMIT_SHA")
+            .or_else(|| git_value(working_directory, &["rev-parse", "HEAD"]))
+            .unwrap_or_default();
+        let service = env_value("DD_SERVICE").unwrap_or_else(|| {
+            repository_url
+                .trim_end_matches('/')
+                .trim_end_matches(".git")
+                .rsplit(['/', ':'])
+                .next()
+                .unwrap_or_default()
+                .to_string()
+        });
+        let env = env_value("DD_ENV").unwrap_or_else(|| String::from("none"));
+        Self { repository_url, commit_sha, service, env }
+    }
+
+    // Stable hash of the key used as the cache folder name
+    fn hash(&self) -> String {
+        // FNV-1a, so the value doesn't depend on the std hasher implementation
+        let mut hash: u64 = 0xcbf29ce484222325;
+        for field in [&self.repository_url, &self.commit_sha, &self.service, &self.env] {
+            for byte in field.bytes().chain([0u8]) {
+                hash ^= byte as u64;
+                hash = hash.wrapping_mul(0x100000001b3);
+            }
+        }
+        format!("{:016x}", hash)
+    }
+}
+
+fn env_value(name: &str) -> Option<String> {
+    env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+fn git_value(working_directory: Option<&str>, args: &[&str]) -> Option<String> {
+    let mut command = Command::new("git");
+    if let Some(working_directory) = working_directory {
+        command.current_dir(working_directory);
+    }
+    let output = command.args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let value = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if value.is_empty() { None } else { Some(value) }
+}
+
+/********************************
+    Cache
+*********************************/
+
+#[derive(Serialize, Deserialize)]
+struct CacheEntry<T> {
+    created_at: u64,
+    key: CacheKey,
+    value: T,
+}
+
+#[derive(Debug, Clone)]
+pub struct TestOptimizationCache {
+    directory: PathBuf,
+    ttl: Duration,
+    key: CacheKey,
+}
+
+impl TestOptimizationCache {
+    #[allow(dead_code)]
+    pub fn new(directory: impl Into<PathBuf>, key: CacheKey) -> Self {
+        Self { directory: directory.into(), ttl: DEFAULT_TTL, key }
+    }
+
+    // Builds the cache from DD_TEST_OPTIMIZATION_CACHE_DIR and DD_TEST_OPTIMIZATION_CACHE_TTL (in seconds)
+    #[allow(dead_code)]
+    pub fn from_env(working_directory: Option<&str>) -> Option<Self> {
+        let directory = env_value(CACHE_DIR_ENV)?;
+        let mut cache = Self::new(directory, CacheKey::discover(working_directory));
+        if let Some(ttl) = env_value(CACHE_TTL_ENV).and_then(|ttl| ttl.parse::<u64>().ok()) {
+            cache = cache.with_ttl(Duration::from_secs(ttl));
+        }
+        Some(cache)
+    }
+
+    #[allow(dead_code)]
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn key(&self) -> &CacheKey {
+        &self.key
+    }
+
+    #[allow(dead_code)]
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    // Folder holding the entries for the current key
+    #[allow(dead_code)]
+    pub fn path(&self) -> PathBuf {
+        self.directory.join(self.key.hash())
+    }
+
+    // Removes every entry for the current key
+    #[allow(dead_code)]
+    pub fn invalidate(&self) -> io::Result<()> {
+        remove_dir(&self.path())
+    }
+
+    // Removes every entry in the cache directory, for all keys
+    #[allow(dead_code)]
+    pub fn clear(&self) -> io::Result<()> {
+        remove_dir(&self.directory)
+    }
+
+    #[allow(dead_code)]
+    pub fn get<T: DeserializeOwned>(&self, name: &str) -> Option<T> {
+        let content = fs::read(self.entry_path(name)).ok()?;
+        let entry: CacheEntry<T> = serde_json::from_slice(&content).ok()?;
+        let age = unix_now().saturating_sub(entry.created_at);
+        if entry.key != self.key || age > self.ttl.as_secs() {
+            return None;
+        }
+        Some(entry.value)
+    }
+
+    #[allow(dead_code)]
+    pub fn set<T: Serialize>(&self, name: &str, value: &T) -> io::Result<()> {
+        let folder = self.path();
+        fs::create_dir_all(&folder)?;
+        let entry = CacheEntry { created_at: unix_now(), key: self.key.clone(), value };
+        let content = serde_json::to_vec(&entry).map_err(io::Error::other)?;
+        // Write to a temporary file and rename it so concurrent writers never read a partial entry,
+        // the counter keeps the name unique between the threads of a process
+        let write_id = TEMP_FILE_COUNTER.fetch_add(1, Ordering::Relaxed);
+        let temp_path = folder.join(format!("{}.{}.{}.tmp", name, process::id(), write_id));
+        fs::write(&temp_path, content)?;
+        fs::rename(&temp_path, self.entry_path(name))
+    }
+
+    fn entry_path(&self, name: &str) -> PathBuf {
+        self.path().join(format!("{}.json", name))
+    }
+}
+
+static TEMP_FILE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+fn remove_dir(path: &Path) -> io::Result<()> {
+    match fs::remove_dir_all(path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
@@ -0,0 +1,132 @@
+use std::cell::RefCell;
+#[cfg(feature = "tokio")]
+use std::future::Future;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/********************************
+    Current test context
+*********************************/
+
+// Entered scopes are ordered by sequence, so the innermost one wins between the thread and the task contexts
+static NEXT_SEQUENCE: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Debug, Clone, Copy)]
+struct ContextEntry {
+    sequence: u64,
+    test_id: Option<u64>,
+    parent_id: u64,
+    trace_id: u64,
+}
+
+impl ContextEntry {
+    fn new(test_id: Option<u64>, parent_id: u64, trace_id: u64) -> Self {
+        Self { sequence: NEXT_SEQUENCE.fetch_add(1, Ordering::Relaxed), test_id, parent_id, trace_id }
+    }
+}
+
+thread_local! {
+    // Entered scopes, the last one is the current one
+    static CURRENT_CONTEXT: RefCell<Vec<ContextEntry>> = const { RefCell::new(Vec::new()) };
+}
+
+#[cfg(feature = "tokio")]
+tokio::task_local! {
+    static TASK_CONTEXT: ContextEntry;
+}
+
+fn current_entry() -> Option<ContextEntry> {
+    let thread_entry = CURRENT_CONTEXT.with(|context| context.borrow().last().copied());
+    #[cfg(feature = "tokio")]
+    if let Ok(task_entry) = TASK_CONTEXT.try_with(|entry| *entry) {
+        return match thread_entry {
+            Some(thread_entry) if thread_entry.sequence > task_entry.sequence => Some(thread_entry),
+            _ => Some(task_entry),
+        };
+    }
+    thread_entry
+}
+
+// Id of the innermost entered test or span, used as parent of new spans
+#[allow(dead_code)]
+pub fn current_parent_id() -> Option<u64> {
+    current_entry().map(|entry| entry.parent_id)
+}
+
+// Trace of the innermost entered test or span
+#[allow(dead_code)]
+pub fn current_trace_id() -> Option<u64> {
+    current_entry().map(|entry| entry.trace_id)
+}
+
+// Trace of an entered test or span, looked up by its id in the current context
+pub(crate) fn entered_trace_id(id: u64) -> Option<u64> {
+    let thread_entry = CURRENT_CONTEXT.with(|context| context.borrow().iter().rev().find(|entry| entry.parent_id == id).copied());
+    #[cfg(feature = "tokio")]
+    if thread_entry.is_none() {
+        return TASK_CONTEXT.try_with(|entry| *entry).ok().filter(|entry| entry.parent_id == id).map(|entry| entry.trace_id);
+    }
+    thread_entry.map(|entry| entry.trace_id)
+}
+
+// Id of the test owning the innermost entered test or span
+#[allow(dead_code)]
+pub fn current_test_id() -> Option<u64> {
+    current_entry().and_then(|entry| entry.test_id)
+}
+
+pub(crate) fn enter(test_id: Option<u64>, parent_id: u64, trace_id: u64) -> ContextGuard {
+    let entry = ContextEntry::new(test_id, parent_id, trace_id);
+    CURRENT_CONTEXT.with(|context| context.borrow_mut().push(entry));
+    ContextGuard { sequence: entry.sequence, parent_id, _not_send: PhantomData }
+}
+
+#[cfg(feature = "tokio")]
+pub(crate) fn scope<F: Future>(
+    test_id: Option<u64>,
+    parent_id: u64,
+    trace_id: u64,
+    future: F,
+) -> impl Future<Output = F::Output> {
+    TASK_CONTEXT.scope(ContextEntry::new(test_id, parent_id, trace_id), future)
+}
+
+// Runs the future in the current context, for tasks spawned from a test
+#[cfg(feature = "tokio")]
+#[allow(dead_code)]
+pub fn in_current_context<F: Future>(future: F) -> impl Future<Output = F::Output> {
+    let entry = current_entry();
+    async move {
+        match entry {
+            Some(entry) => TASK_CONTEXT.scope(entry, future).await,
+            None => future.await,
+        }
+    }
+}
+
+// Restores the previous context when dropped, it can't leave the thread that created it
+#[derive(Debug)]
+pub struct ContextGuard {
+    sequence: u64,
+    parent_id: u64,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl ContextGuard {
+    #[allow(dead_code)]
+    pub fn id(&self) -> u64 {
+        self.parent_id
+    }
+}
+
+impl Drop for ContextGuard {
+    fn drop(&mut self) {
+        CURRENT_CONTEXT.with(|context| {
+            let mut context = context.borrow_mut();
+            // Guards are usually dropped in reverse order, but remove the right entry if they aren't
+            if let Some(idx) = context.iter().rposition(|entry| entry.sequence == self.sequence) {
+                context.remove(idx);
+            }
+        });
+    }
+}
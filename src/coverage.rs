@@ -0,0 +1,629 @@
+use crate::libcivisibility_bindings::*;
+use std::collections::BTreeMap;
+#[cfg(feature = "llvm-profile")]
+use std::ffi::c_int;
+use std::ffi::{c_char, c_void, CString};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::ptr::null_mut;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::{env, fmt, fs, io};
+
+/********************************
+    Coverage bitmap
+*********************************/
+
+// Executed lines of a file, encoded the way the backend expects it:
+// line N (1-based) is the bit `0x80 >> ((N - 1) % 8)` of the byte `(N - 1) / 8`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CoverageBitmap {
+    bytes: Vec<u8>,
+}
+
+impl CoverageBitmap {
+    #[allow(dead_code)]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[allow(dead_code)]
+    pub fn from_lines(lines: impl IntoIterator<Item = u32>) -> Self {
+        let mut bitmap = Self::new();
+        for line in lines {
+            bitmap.set_line(line);
+        }
+        bitmap
+    }
+
+    #[allow(dead_code)]
+    pub fn from_ranges(ranges: impl IntoIterator<Item = RangeInclusive<u32>>) -> Self {
+        let mut bitmap = Self::new();
+        for range in ranges {
+            bitmap.set_range(range);
+        }
+        bitmap
+    }
+
+    // Wraps an already encoded bitmap
+    #[allow(dead_code)]
+    pub fn from_bytes(bytes: Vec<u8>) -> Self {
+        Self { bytes }
+    }
+
+    // Marks a line as executed, line 0 is ignored since lines are 1-based
+    #[allow(dead_code)]
+    pub fn set_line(&mut self, line: u32) {
+        if line == 0 {
+            return;
+        }
+        let index = (line - 1) as usize;
+        let byte_index = index / 8;
+        if byte_index >= self.bytes.len() {
+            self.bytes.resize(byte_index + 1, 0);
+        }
+        self.bytes[byte_index] |= 0x80 >> (index % 8);
+    }
+
+    #[allow(dead_code)]
+    pub fn set_range(&mut self, lines: RangeInclusive<u32>) {
+        for line in lines {
+            self.set_line(line);
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn contains(&self, line: u32) -> bool {
+        if line == 0 {
+            return false;
+        }
+        let index = (line - 1) as usize;
+        self.bytes.get(index / 8).is_some_and(|byte| byte & (0x80 >> (index % 8)) != 0)
+    }
+
+    // Merges the executed lines of another bitmap into this one
+    #[allow(dead_code)]
+    pub fn union_with(&mut self, other: &CoverageBitmap) {
+        if other.bytes.len() > self.bytes.len() {
+            self.bytes.resize(other.bytes.len(), 0);
+        }
+        for (byte, other_byte) in self.bytes.iter_mut().zip(&other.bytes) {
+            *byte |= other_byte;
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn lines(&self) -> impl Iterator<Item = u32> + '_ {
+        self.bytes.iter().enumerate().flat_map(|(byte_index, byte)| {
+            (0..8u32)
+                .filter(move |bit| byte & (0x80 >> bit) != 0)
+                .map(move |bit| byte_index as u32 * 8 + bit + 1)
+        })
+    }
+
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.bytes.iter().all(|byte| *byte == 0)
+    }
+
+    #[allow(dead_code)]
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+/********************************
+    Coverage report
+*********************************/
+
+#[derive(Debug)]
+pub enum CoverageError {
+    Io(io::Error),
+    Parse(String),
+}
+
+impl fmt::Display for CoverageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CoverageError::Io(e) => write!(f, "failed to read the coverage data: {}", e),
+            CoverageError::Parse(e) => write!(f, "failed to parse the coverage data: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for CoverageError {}
+
+// Executed lines per file, as produced for a single test
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CoverageReport {
+    files: BTreeMap<String, CoverageBitmap>,
+}
+
+impl CoverageReport {
+    #[allow(dead_code)]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[allow(dead_code)]
+    pub fn add_line(&mut self, file: impl Into<String>, line: u32) {
+        self.files.entry(file.into()).or_default().set_line(line);
+    }
+
+    #[allow(dead_code)]
+    pub fn add_bitmap(&mut self, file: impl Into<String>, bitmap: &CoverageBitmap) {
+        self.files.entry(file.into()).or_default().union_with(bitmap);
+    }
+
+    #[allow(dead_code)]
+    pub fn get(&self, file: impl AsRef<str>) -> Option<&CoverageBitmap> {
+        self.files.get(file.as_ref())
+    }
+
+    #[allow(dead_code)]
+    pub fn files(&self) -> impl Iterator<Item = (&str, &CoverageBitmap)> {
+        self.files.iter().map(|(file, bitmap)| (file.as_str(), bitmap))
+    }
+
+    #[allow(dead_code)]
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    // Keeps only the files inside `base_dir`, with their paths made relative to it.
+    // Relative paths are considered to be already relative to `base_dir`.
+    #[allow(dead_code)]
+    pub fn relative_to(&self, base_dir: impl AsRef<Path>) -> Self {
+        let base_dir = base_dir.as_ref();
+        let mut report = Self::new();
+        for (file, bitmap) in self.files() {
+            let path = Path::new(file);
+            let relative_path = if path.is_absolute() {
+                match path.strip_prefix(base_dir) {
+                    Ok(relative_path) => relative_path,
+                    Err(_) => continue,
+                }
+            } else {
+                path
+            };
+            report.add_bitmap(relative_path.to_string_lossy().replace('\\', "/"), bitmap);
+        }
+        report
+    }
+
+    #[allow(dead_code)]
+    pub fn from_lcov_file(path: impl AsRef<Path>) -> Result<Self, CoverageError> {
+        Self::from_lcov_str(&fs::read_to_string(path).map_err(CoverageError::Io)?)
+    }
+
+    // Parses an lcov tracefile, only lines with a positive execution count are kept
+    #[allow(dead_code)]
+    pub fn from_lcov_str(content: &str) -> Result<Self, CoverageError> {
+        let mut report = Self::new();
+        let mut current_file: Option<&str> = None;
+        for (idx, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if let Some(file) = line.strip_prefix("SF:") {
+                current_file = Some(file);
+                report.files.entry(file.to_string()).or_default();
+            } else if let Some(data) = line.strip_prefix("DA:") {
+                let file = current_file.ok_or_else(|| {
+                    CoverageError::Parse(format!("line {}: DA record outside of a source file record", idx + 1))
+                })?;
+                let mut fields = data.split(',');
+                let line_number = fields.next().and_then(|value| value.trim().parse::<u32>().ok());
+                let count = fields.next().and_then(|value| value.trim().parse::<f64>().ok());
+                match (line_number, count) {
+                    (Some(line_number), Some(count)) if count > 0.0 => report.add_line(file, line_number),
+                    (Some(_), Some(_)) => {}
+                    _ => return Err(CoverageError::Parse(format!("line {}: invalid DA record `{}`", idx + 1, line))),
+                }
+            } else if line == "end_of_record" {
+                current_file = None;
+            }
+        }
+        // Files without executed lines are not covered by the test
+        report.files.retain(|_, bitmap| !bitmap.is_empty());
+        Ok(report)
+    }
+
+    #[cfg(feature = "llvm-cov")]
+    #[allow(dead_code)]
+    pub fn from_llvm_json_file(path: impl AsRef<Path>) -> Result<Self, CoverageError> {
+        Self::from_llvm_json_str(&fs::read_to_string(path).map_err(CoverageError::Io)?)
+    }
+
+    // Parses the output of `llvm-cov export -format=text`
+    #[cfg(feature = "llvm-cov")]
+    #[allow(dead_code)]
+    pub fn from_llvm_json_str(content: &str) -> Result<Self, CoverageError> {
+        let export: serde_json::Value = serde_json::from_str(content).map_err(|e| CoverageError::Parse(e.to_string()))?;
+        let data = export["data"]
+            .as_array()
+            .ok_or_else(|| CoverageError::Parse(String::from("missing `data` array")))?;
+        let mut report = Self::new();
+        for file in data.iter().filter_map(|export| export["files"].as_array()).flatten() {
+            let filename = file["filename"]
+                .as_str()
+                .ok_or_else(|| CoverageError::Parse(String::from("missing file name")))?;
+            let segments = file["segments"]
+                .as_array()
+                .ok_or_else(|| CoverageError::Parse(format!("missing segments for `{}`", filename)))?
+                .iter()
+                .map(LlvmSegment::parse)
+                .collect::<Result<Vec<_>, _>>()?;
+            let bitmap = llvm_segments_to_bitmap(&segments);
+            if !bitmap.is_empty() {
+                report.add_bitmap(filename, &bitmap);
+            }
+        }
+        Ok(report)
+    }
+}
+
+#[cfg(feature = "llvm-cov")]
+struct LlvmSegment {
+    line: u32,
+    count: u64,
+    has_count: bool,
+    is_region_entry: bool,
+    is_gap_region: bool,
+}
+
+#[cfg(feature = "llvm-cov")]
+impl LlvmSegment {
+    // Segments are exported as [line, col, count, has_count, is_region_entry, is_gap_region]
+    fn parse(value: &serde_json::Value) -> Result<Self, CoverageError> {
+        let invalid = || CoverageError::Parse(format!("invalid segment `{}`", value));
+        let fields = value.as_array().filter(|fields| fields.len() >= 5).ok_or_else(invalid)?;
+        Ok(Self {
+            line: fields[0].as_u64().and_then(|line| u32::try_from(line).ok()).ok_or_else(invalid)?,
+            count: fields[2].as_u64().ok_or_else(invalid)?,
+            has_count: fields[3].as_bool().ok_or_else(invalid)?,
+            is_region_entry: fields[4].as_bool().ok_or_else(invalid)?,
+            is_gap_region: fields.get(5).and_then(|value| value.as_bool()).unwrap_or(false),
+        })
+    }
+
+    fn is_start_of_region(&self) -> bool {
+        !self.is_gap_region && self.has_count && self.is_region_entry
+    }
+}
+
+// Same line coverage rules as `llvm-cov`: a line is executed when the segment wrapping it
+// or any region starting on it has a positive count.
+#[cfg(feature = "llvm-cov")]
+fn llvm_segments_to_bitmap(segments: &[LlvmSegment]) -> CoverageBitmap {
+    let mut bitmap = CoverageBitmap::new();
+    let (Some(first), Some(last)) = (segments.first(), segments.last()) else {
+        return bitmap;
+    };
+    let mut wrapped: Option<&LlvmSegment> = None;
+    let mut idx = 0;
+    for line in first.line..=last.line {
+        let start = idx;
+        while idx < segments.len() && segments[idx].line == line {
+            idx += 1;
+        }
+        let line_segments = &segments[start..idx];
+        let start_of_skipped_region = line_segments
+            .first()
+            .is_some_and(|segment| !segment.has_count && segment.is_region_entry);
+        let mut count = wrapped.map_or(0, |segment| segment.count);
+        let mut mapped = wrapped.is_some_and(|segment| segment.has_count);
+        for segment in line_segments.iter().filter(|segment| segment.is_start_of_region()) {
+            mapped = true;
+            count = count.max(segment.count);
+        }
+        if mapped && !start_of_skipped_region && count > 0 {
+            bitmap.set_line(line);
+        }
+        if let Some(segment) = line_segments.last() {
+            wrapped = Some(segment);
+        }
+    }
+    bitmap
+}
+
+/********************************
+    Coverage summary
+*********************************/
+
+// Total line coverage of a whole run, as reported in the session
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CoverageSummary {
+    pub lines_found: u64,
+    pub lines_hit: u64,
+}
+
+impl CoverageSummary {
+    #[allow(dead_code)]
+    pub fn from_lcov_file(path: impl AsRef<Path>) -> Result<Self, CoverageError> {
+        Self::from_lcov_str(&fs::read_to_string(path).map_err(CoverageError::Io)?)
+    }
+
+    // Sums the LF/LH records of each file, counting the DA records of files without them
+    #[allow(dead_code)]
+    pub fn from_lcov_str(content: &str) -> Result<Self, CoverageError> {
+        let mut summary = Self::default();
+        // (LF, LH, DA lines, DA lines hit) of the current file
+        let mut file = (None, None, 0u64, 0u64);
+        for (idx, line) in content.lines().enumerate() {
+            let line = line.trim();
+            let invalid = || CoverageError::Parse(format!("line {}: invalid record `{}`", idx + 1, line));
+            if let Some(value) = line.strip_prefix("LF:") {
+                file.0 = Some(value.trim().parse::<u64>().map_err(|_| invalid())?);
+            } else if let Some(value) = line.strip_prefix("LH:") {
+                file.1 = Some(value.trim().parse::<u64>().map_err(|_| invalid())?);
+            } else if let Some(data) = line.strip_prefix("DA:") {
+                let count = data.split(',').nth(1).and_then(|value| value.trim().parse::<f64>().ok()).ok_or_else(invalid)?;
+                file.2 += 1;
+                if count > 0.0 {
+                    file.3 += 1;
+                }
+            } else if line == "end_of_record" {
+                summary.lines_found += file.0.unwrap_or(file.2);
+                summary.lines_hit += file.1.unwrap_or(file.3);
+                file = (None, None, 0, 0);
+            }
+        }
+        Ok(summary)
+    }
+
+    // Percentage of executed lines, between 0 and 100
+    #[allow(dead_code)]
+    pub fn percentage(&self) -> f64 {
+        if self.lines_found == 0 {
+            return 0.0;
+        }
+        (self.lines_hit as f64 * 100.0 / self.lines_found as f64).min(100.0)
+    }
+}
+
+/********************************
+    LLVM profile runtime
+*********************************/
+
+// Counters of a binary built with `-C instrument-coverage`, only available with the `llvm-profile` feature
+#[cfg(feature = "llvm-profile")]
+extern "C" {
+    fn __llvm_profile_reset_counters();
+    fn __llvm_profile_set_filename(filename: *const c_char);
+    fn __llvm_profile_write_file() -> c_int;
+}
+
+// Helpers for custom harnesses collecting the coverage of each test from an instrumented binary:
+// reset the counters before a test, dump them after it, and convert the dump with the llvm tools.
+#[derive(Debug, Clone)]
+pub struct LlvmCoverage {
+    llvm_profdata: PathBuf,
+    llvm_cov: PathBuf,
+    binary: PathBuf,
+}
+
+impl LlvmCoverage {
+    // Uses the LLVM_PROFDATA and LLVM_COV variables if set (as cargo-llvm-cov does), otherwise the tools in PATH
+    #[allow(dead_code)]
+    pub fn new() -> io::Result<Self> {
+        Ok(Self {
+            llvm_profdata: env::var_os("LLVM_PROFDATA").map_or_else(|| PathBuf::from("llvm-profdata"), PathBuf::from),
+            llvm_cov: env::var_os("LLVM_COV").map_or_else(|| PathBuf::from("llvm-cov"), PathBuf::from),
+            binary: env::current_exe()?,
+        })
+    }
+
+    #[allow(dead_code)]
+    pub fn with_tools(mut self, llvm_profdata: impl Into<PathBuf>, llvm_cov: impl Into<PathBuf>) -> Self {
+        self.llvm_profdata = llvm_profdata.into();
+        self.llvm_cov = llvm_cov.into();
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn with_binary(mut self, binary: impl Into<PathBuf>) -> Self {
+        self.binary = binary.into();
+        self
+    }
+
+    // Clears the counters so the next profile only contains the lines executed from now on
+    #[cfg(feature = "llvm-profile")]
+    #[allow(dead_code)]
+    pub fn reset_counters() {
+        unsafe { __llvm_profile_reset_counters() }
+    }
+
+    // Writes the current counters to a `.profraw` file
+    #[cfg(feature = "llvm-profile")]
+    #[allow(dead_code)]
+    pub fn write_profile(path: impl AsRef<Path>) -> io::Result<()> {
+        let path_cstring = CString::new(path.as_ref().to_string_lossy().into_owned()).map_err(io::Error::other)?;
+        // The runtime copies the file name, it only has to live for the call
+        let result = unsafe {
+            __llvm_profile_set_filename(path_cstring.as_ptr());
+            __llvm_profile_write_file()
+        };
+        if result == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::other("failed to write the llvm profile"))
+        }
+    }
+
+    // Converts a `.profraw` file into a coverage report using llvm-profdata and llvm-cov
+    #[allow(dead_code)]
+    pub fn report_from_profile(&self, profraw: impl AsRef<Path>) -> Result<CoverageReport, CoverageError> {
+        let profraw = profraw.as_ref();
+        let profdata = profraw.with_extension("profdata");
+        run_tool(
+            Command::new(&self.llvm_profdata)
+                .arg("merge")
+                .arg("-sparse")
+                .arg(profraw)
+                .arg("-o")
+                .arg(&profdata),
+        )?;
+        let lcov = run_tool(
+            Command::new(&self.llvm_cov)
+                .arg("export")
+                .arg("-format=lcov")
+                .arg(format!("-instr-profile={}", profdata.display()))
+                .arg(&self.binary),
+        );
+        _ = fs::remove_file(&profdata);
+        CoverageReport::from_lcov_str(&lcov?)
+    }
+}
+
+fn run_tool(command: &mut Command) -> Result<String, CoverageError> {
+    let output = command.output().map_err(CoverageError::Io)?;
+    if !output.status.success() {
+        return Err(CoverageError::Io(io::Error::other(format!(
+            "{:?} failed: {}",
+            command,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/********************************
+    Coverage batching
+*********************************/
+
+// Coverage of a single test, owning the data until it's sent
+#[derive(Debug, Clone)]
+pub(crate) struct TestCoverageRecord {
+    pub session_id: u64,
+    pub suite_id: u64,
+    pub test_id: u64,
+    pub files: Vec<(CString, Vec<u8>)>,
+}
+
+// Sends the records in a single payload
+pub(crate) fn send_coverage_records(records: &[TestCoverageRecord]) {
+    if records.is_empty() {
+        return;
+    }
+    // The file arrays must stay alive until the payload is sent
+    let mut coverage_files: Vec<Vec<topt_TestCoverageFile>> = records
+        .iter()
+        .map(|record| {
+            record
+                .files
+                .iter()
+                .map(|(filename, bitmap)| topt_TestCoverageFile {
+                    filename: filename.as_ptr() as *mut c_char,
+                    bitmap: if bitmap.is_empty() { null_mut() } else { bitmap.as_ptr() as *mut c_void },
+                    bitmap_len: bitmap.len(),
+                })
+                .collect()
+        })
+        .collect();
+    let mut coverages: Vec<topt_TestCoverage> = records
+        .iter()
+        .zip(coverage_files.iter_mut())
+        .map(|(record, files)| topt_TestCoverage {
+            session_id: record.session_id,
+            suite_id: record.suite_id,
+            test_id: record.test_id,
+            files: files.as_mut_ptr(),
+            files_len: files.len(),
+        })
+        .collect();
+    unsafe { topt_send_code_coverage_payload(coverages.as_mut_ptr(), coverages.len()) };
+}
+
+// Accumulates the coverage of many tests and sends it in payloads of up to `max_batch_size` tests.
+// Remaining records are sent by `close`, which the session calls before shutting the library down.
+// Once closed, new records are dropped so a clone kept past the session never sends anything.
+#[derive(Debug)]
+pub struct CoverageBatcher {
+    max_batch_size: usize,
+    pending: Mutex<Vec<TestCoverageRecord>>,
+    closed: AtomicBool,
+}
+
+impl CoverageBatcher {
+    #[allow(dead_code)]
+    pub fn new(max_batch_size: usize) -> Self {
+        Self { max_batch_size: max_batch_size.max(1), pending: Mutex::new(Vec::new()), closed: AtomicBool::new(false) }
+    }
+
+    #[allow(dead_code)]
+    pub fn max_batch_size(&self) -> usize {
+        self.max_batch_size
+    }
+
+    // Number of tests waiting to be sent
+    #[allow(dead_code)]
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.lock().is_empty()
+    }
+
+    #[allow(dead_code)]
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+
+    // Returns false when the record is dropped because the batcher is closed
+    pub(crate) fn add(&self, record: TestCoverageRecord) -> bool {
+        let batch = {
+            let mut pending = self.lock();
+            if self.is_closed() {
+                return false;
+            }
+            pending.push(record);
+            if pending.len() < self.max_batch_size {
+                return true;
+            }
+            std::mem::take(&mut *pending)
+        };
+        // Send outside of the lock so other tests can keep adding records
+        send_coverage_records(&batch);
+        true
+    }
+
+    #[allow(dead_code)]
+    pub fn flush(&self) {
+        let pending = std::mem::take(&mut *self.lock());
+        for batch in pending.chunks(self.max_batch_size) {
+            send_coverage_records(batch);
+        }
+    }
+
+    // Final flush, the records added afterwards are dropped
+    pub(crate) fn close(&self) {
+        let pending = {
+            let mut pending = self.lock();
+            self.closed.store(true, Ordering::Release);
+            std::mem::take(&mut *pending)
+        };
+        for batch in pending.chunks(self.max_batch_size) {
+            send_coverage_records(batch);
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<TestCoverageRecord>> {
+        self.pending.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Drop for CoverageBatcher {
+    fn drop(&mut self) {
+        // A closed batcher may outlive the session, the library is shut down by then
+        if !self.is_closed() {
+            self.flush();
+        }
+    }
+}
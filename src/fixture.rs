@@ -0,0 +1,151 @@
+use crate::test_optimization::*;
+#[cfg(feature = "fixture")]
+use std::path::Path;
+#[cfg(feature = "fixture")]
+use std::{fmt, fs, io};
+
+#[cfg(feature = "fixture")]
+pub static FIXTURE_ENV: &str = "DD_TEST_OPTIMIZATION_FIXTURE";
+
+/********************************
+    Fixture
+*********************************/
+
+// Settings and test lists returned by the session getters instead of the backend ones
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(default))]
+pub struct Fixture {
+    pub settings: Settings,
+    pub flaky_test_retries_settings: FlakyTestRetriesSettings,
+    pub known_tests: KnownTests,
+    #[cfg_attr(feature = "serde", serde(with = "skippable_tests_list"))]
+    pub skippable_tests: SkippableTests,
+    #[cfg_attr(feature = "serde", serde(with = "test_management_tests_list"))]
+    pub test_management_tests: TestManagementTests,
+}
+
+impl Fixture {
+    #[allow(dead_code)]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[allow(dead_code)]
+    pub fn with_settings(mut self, settings: Settings) -> Self {
+        self.settings = settings;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn with_flaky_test_retries_settings(mut self, flaky_test_retries_settings: FlakyTestRetriesSettings) -> Self {
+        self.flaky_test_retries_settings = flaky_test_retries_settings;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn with_known_tests(mut self, known_tests: KnownTests) -> Self {
+        self.known_tests = known_tests;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn with_skippable_tests(mut self, skippable_tests: SkippableTests) -> Self {
+        self.skippable_tests = skippable_tests;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn with_test_management_tests(mut self, test_management_tests: TestManagementTests) -> Self {
+        self.test_management_tests = test_management_tests;
+        self
+    }
+
+    #[cfg(feature = "fixture")]
+    #[allow(dead_code)]
+    pub fn from_json_str(content: &str) -> Result<Self, FixtureError> {
+        serde_json::from_str(content).map_err(|e| FixtureError::Parse(e.to_string()))
+    }
+
+    #[cfg(feature = "fixture")]
+    #[allow(dead_code)]
+    pub fn from_toml_str(content: &str) -> Result<Self, FixtureError> {
+        toml::from_str(content).map_err(|e| FixtureError::Parse(e.to_string()))
+    }
+
+    // Loads a fixture file, using TOML for `.toml` files and JSON for everything else
+    #[cfg(feature = "fixture")]
+    #[allow(dead_code)]
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, FixtureError> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path).map_err(FixtureError::Io)?;
+        if path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("toml")) {
+            Self::from_toml_str(&content)
+        } else {
+            Self::from_json_str(&content)
+        }
+    }
+
+    // Loads the fixture file set in DD_TEST_OPTIMIZATION_FIXTURE, if any
+    #[cfg(feature = "fixture")]
+    #[allow(dead_code)]
+    pub fn from_env() -> Option<Result<Self, FixtureError>> {
+        let path = std::env::var(FIXTURE_ENV).ok().filter(|path| !path.is_empty())?;
+        Some(Self::from_file(path))
+    }
+}
+
+#[cfg(feature = "fixture")]
+#[derive(Debug)]
+pub enum FixtureError {
+    Io(io::Error),
+    Parse(String),
+}
+
+#[cfg(feature = "fixture")]
+impl fmt::Display for FixtureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FixtureError::Io(e) => write!(f, "failed to read the fixture file: {}", e),
+            FixtureError::Parse(e) => write!(f, "failed to parse the fixture file: {}", e),
+        }
+    }
+}
+
+#[cfg(feature = "fixture")]
+impl std::error::Error for FixtureError {}
+
+// Skippable and test management tests are written as flat lists in fixture files, sorted by name
+
+#[cfg(feature = "serde")]
+mod skippable_tests_list {
+    use crate::test_optimization::{SkippableTest, SkippableTests};
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(tests: &SkippableTests, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut tests: Vec<_> = tests.iter().collect();
+        tests.sort_by(|a, b| (&a.suite_name, &a.test_name).cmp(&(&b.suite_name, &b.test_name)));
+        serializer.collect_seq(tests)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<SkippableTests, D::Error> {
+        Ok(Vec::<SkippableTest>::deserialize(deserializer)?.into_iter().collect())
+    }
+}
+
+#[cfg(feature = "serde")]
+mod test_management_tests_list {
+    use crate::test_optimization::{TestManagementTest, TestManagementTests};
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(tests: &TestManagementTests, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut tests: Vec<_> = tests.iter().collect();
+        tests.sort_by(|a, b| {
+            (&a.module_name, &a.suite_name, &a.test_name).cmp(&(&b.module_name, &b.suite_name, &b.test_name))
+        });
+        serializer.collect_seq(tests)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<TestManagementTests, D::Error> {
+        Ok(Vec::<TestManagementTest>::deserialize(deserializer)?.into_iter().collect())
+    }
+}
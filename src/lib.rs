@@ -1,4 +1,18 @@
 pub mod test_optimization;
+#[cfg(feature = "cache")]
+pub mod cache;
+pub mod context;
+pub mod coverage;
+pub mod fixture;
+pub mod mock_tracer;
+pub mod naming;
+pub mod paths;
+pub mod propagation;
+pub mod tags;
+#[cfg(feature = "log")]
+pub mod test_logger;
+#[cfg(feature = "tracing")]
+pub mod tracing_layer;
 #[cfg(test)]
 mod tests;
 mod libcivisibility_bindings;
@@ -0,0 +1,990 @@
+use crate::tags;
+use crate::test_optimization::{MockSpan, MockTracer, TestStatus};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Display, Formatter, Write};
+use std::marker::PhantomData;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+use std::{env, fs, thread};
+
+/********************************
+    MockTracer queries
+*********************************/
+
+// Filters over a set of mock spans, each filter narrows the current selection
+#[derive(Debug, Clone, Default)]
+pub struct SpanQuery {
+    spans: Vec<MockSpan>,
+}
+
+impl SpanQuery {
+    #[allow(dead_code)]
+    pub fn new(spans: Vec<MockSpan>) -> Self {
+        Self { spans }
+    }
+
+    #[allow(dead_code)]
+    pub fn by_operation(self, operation_name: impl AsRef<str>) -> Self {
+        let operation_name = operation_name.as_ref();
+        self.filter(|span| span.operation_name == operation_name)
+    }
+
+    // Spans with a string tag equal to `value`, or a number tag if `value` parses as a number
+    #[allow(dead_code)]
+    pub fn with_tag(self, key: impl AsRef<str>, value: impl AsRef<str>) -> Self {
+        let (key, value) = (key.as_ref(), value.as_ref());
+        self.filter(|span| tag_matches(span, key, value))
+    }
+
+    #[allow(dead_code)]
+    pub fn children_of(self, parent_span_id: u64) -> Self {
+        self.filter(|span| span.parent_span_id == parent_span_id)
+    }
+
+    #[allow(dead_code)]
+    pub fn in_trace(self, trace_id: u64) -> Self {
+        self.filter(|span| span.trace_id == trace_id)
+    }
+
+    #[allow(dead_code)]
+    pub fn filter(mut self, predicate: impl Fn(&MockSpan) -> bool) -> Self {
+        self.spans.retain(|span| predicate(span));
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn spans(&self) -> &[MockSpan] {
+        &self.spans
+    }
+
+    #[allow(dead_code)]
+    pub fn into_spans(self) -> Vec<MockSpan> {
+        self.spans
+    }
+
+    #[allow(dead_code)]
+    pub fn first(&self) -> Option<&MockSpan> {
+        self.spans.first()
+    }
+
+    #[allow(dead_code)]
+    pub fn len(&self) -> usize {
+        self.spans.len()
+    }
+
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.spans.is_empty()
+    }
+
+    // Span with the operation name, panics listing the selected spans if there is none
+    #[allow(dead_code)]
+    pub fn assert_span_exists(&self, operation_name: impl AsRef<str>) -> &MockSpan {
+        let operation_name = operation_name.as_ref();
+        match self.spans.iter().find(|span| span.operation_name == operation_name) {
+            Some(span) => span,
+            None => panic!("no span with operation `{}` found, spans:\n{}", operation_name, format_spans(&self.spans)),
+        }
+    }
+}
+
+impl From<Vec<MockSpan>> for SpanQuery {
+    fn from(spans: Vec<MockSpan>) -> Self {
+        Self::new(spans)
+    }
+}
+
+impl IntoIterator for SpanQuery {
+    type Item = MockSpan;
+    type IntoIter = std::vec::IntoIter<MockSpan>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.spans.into_iter()
+    }
+}
+
+impl MockTracer {
+    #[allow(dead_code)]
+    pub fn query() -> SpanQuery {
+        SpanQuery::new(Self::get_finished_spans())
+    }
+
+    #[allow(dead_code)]
+    pub fn query_open() -> SpanQuery {
+        SpanQuery::new(Self::get_open_spans())
+    }
+
+    // Finished span with the operation name, panics listing the finished spans if there is none
+    #[allow(dead_code)]
+    pub fn assert_span_exists(operation_name: impl AsRef<str>) -> MockSpan {
+        Self::query().assert_span_exists(operation_name).clone()
+    }
+
+    // Panics listing the open spans, except the session ones that stay open until the session is closed
+    #[allow(dead_code)]
+    pub fn assert_no_open_spans() {
+        let open_spans = Self::query_open().filter(|span| !is_session_span(span));
+        if !open_spans.is_empty() {
+            panic!("{} spans are still open:\n{}", open_spans.len(), format_spans(open_spans.spans()));
+        }
+    }
+}
+
+/********************************
+    MockSpan accessors
+*********************************/
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpanKind {
+    Session,
+    Module,
+    Suite,
+    Test,
+    Custom,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpanError {
+    pub error_type: String,
+    pub message: String,
+    pub stacktrace: String,
+}
+
+impl MockSpan {
+    // Kind of span, from the `span.type` tag
+    #[allow(dead_code)]
+    pub fn kind(&self) -> SpanKind {
+        match self.string_tags.get(tags::SPAN_TYPE) {
+            Some(span_type) if span_type == tags::SPAN_TYPE_SESSION => SpanKind::Session,
+            Some(span_type) if span_type == tags::SPAN_TYPE_MODULE => SpanKind::Module,
+            Some(span_type) if span_type == tags::SPAN_TYPE_SUITE => SpanKind::Suite,
+            Some(span_type) if span_type == tags::SPAN_TYPE_TEST => SpanKind::Test,
+            _ => SpanKind::Custom,
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn test_status(&self) -> Option<TestStatus> {
+        match self.string_tags.get(tags::TEST_STATUS)? {
+            status if status == tags::TEST_STATUS_PASS => Some(TestStatus::Pass),
+            status if status == tags::TEST_STATUS_FAIL => Some(TestStatus::Fail),
+            status if status == tags::TEST_STATUS_SKIP => Some(TestStatus::Skip),
+            _ => None,
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn test_module(&self) -> Option<&str> {
+        self.string_tags.get(tags::TEST_MODULE).map(String::as_str)
+    }
+
+    #[allow(dead_code)]
+    pub fn test_suite(&self) -> Option<&str> {
+        self.string_tags.get(tags::TEST_SUITE).map(String::as_str)
+    }
+
+    #[allow(dead_code)]
+    pub fn test_name(&self) -> Option<&str> {
+        self.string_tags.get(tags::TEST_NAME).map(String::as_str)
+    }
+
+    #[allow(dead_code)]
+    pub fn skip_reason(&self) -> Option<&str> {
+        self.string_tags.get(tags::TEST_SKIP_REASON).map(String::as_str)
+    }
+
+    // Error info set on the span, if any
+    #[allow(dead_code)]
+    pub fn error(&self) -> Option<SpanError> {
+        let error_type = self.string_tags.get(tags::ERROR_TYPE);
+        let message = self.string_tags.get(tags::ERROR_MESSAGE);
+        let stacktrace = self.string_tags.get(tags::ERROR_STACK);
+        if error_type.is_none() && message.is_none() && stacktrace.is_none() {
+            return None;
+        }
+        Some(SpanError {
+            error_type: error_type.cloned().unwrap_or_default(),
+            message: message.cloned().unwrap_or_default(),
+            stacktrace: stacktrace.cloned().unwrap_or_default(),
+        })
+    }
+
+    // Time between the start and the finish of the span, `None` if it isn't finished
+    #[allow(dead_code)]
+    pub fn duration(&self) -> Option<Duration> {
+        self.finish_time.duration_since(self.start_time).ok().filter(|_| self.finish_time != SystemTime::UNIX_EPOCH)
+    }
+}
+
+/********************************
+    MockSpan assertions
+*********************************/
+
+impl MockSpan {
+    // Tag value as a string, number tags are formatted
+    #[allow(dead_code)]
+    pub fn tag(&self, key: impl AsRef<str>) -> Option<String> {
+        let key = key.as_ref();
+        self.string_tags.get(key).cloned().or_else(|| self.number_tags.get(key).map(|value| value.to_string()))
+    }
+
+    #[allow(dead_code)]
+    pub fn assert_tag_eq(&self, key: impl AsRef<str>, expected: impl AsRef<str>) -> &Self {
+        let (key, expected) = (key.as_ref(), expected.as_ref());
+        if !tag_matches(self, key, expected) {
+            let found = match self.tag(key) {
+                Some(value) => format!("`{}`", value),
+                None => String::from("no tag"),
+            };
+            panic!(
+                "tag `{}` mismatch on span `{}` ({})\n  expected: `{}`\n     found: {}\n{}",
+                key,
+                self.operation_name,
+                self.span_id,
+                expected,
+                found,
+                format_tags(self)
+            );
+        }
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn assert_status(&self, status: TestStatus) -> &Self {
+        self.assert_tag_eq(tags::TEST_STATUS, status_tag_value(&status))
+    }
+}
+
+// Helper: value of the `test.status` tag for a status
+fn status_tag_value(status: &TestStatus) -> &'static str {
+    match status {
+        TestStatus::Pass => tags::TEST_STATUS_PASS,
+        TestStatus::Fail => tags::TEST_STATUS_FAIL,
+        TestStatus::Skip => tags::TEST_STATUS_SKIP,
+    }
+}
+
+fn is_session_span(span: &MockSpan) -> bool {
+    span.kind() == SpanKind::Session
+}
+
+fn tag_matches(span: &MockSpan, key: &str, value: &str) -> bool {
+    match span.string_tags.get(key) {
+        Some(tag) => tag == value,
+        None => span.number_tags.get(key).is_some_and(|tag| value.parse::<f64>().is_ok_and(|value| value == *tag)),
+    }
+}
+
+// Helper: one line per span, sorted by id, for the failure messages
+fn format_spans(spans: &[MockSpan]) -> String {
+    let mut spans: Vec<&MockSpan> = spans.iter().collect();
+    spans.sort_by_key(|span| span.span_id);
+    let mut result = String::new();
+    for span in spans {
+        let span_type = span.string_tags.get(tags::SPAN_TYPE).map(String::as_str).unwrap_or("");
+        let _ = writeln!(
+            result,
+            "  - `{}` [{}] span_id={} parent_span_id={} trace_id={}",
+            span.operation_name, span_type, span.span_id, span.parent_span_id, span.trace_id
+        );
+    }
+    if result.is_empty() {
+        result.push_str("  (none)\n");
+    }
+    result
+}
+
+// Helper: one line per tag, sorted by key, for the failure messages
+fn format_tags(span: &MockSpan) -> String {
+    let mut tags: Vec<(&String, String)> = span.string_tags.iter().map(|(key, value)| (key, format!("{:?}", value))).collect();
+    tags.extend(span.number_tags.iter().map(|(key, value)| (key, value.to_string())));
+    tags.sort();
+    let mut result = String::from("  tags:\n");
+    for (key, value) in tags {
+        let _ = writeln!(result, "    {} = {}", key, value);
+    }
+    result
+}
+
+/********************************
+    Span tree
+*********************************/
+
+// Span with its children, sorted by start time
+#[derive(Debug, Clone)]
+pub struct SpanNode {
+    pub span: MockSpan,
+    pub open: bool,
+    pub children: Vec<SpanNode>,
+}
+
+impl SpanNode {
+    // Helper: depth first search of a span in this subtree
+    fn find(&self, span_id: u64) -> Option<&SpanNode> {
+        if self.span.span_id == span_id {
+            return Some(self);
+        }
+        self.children.iter().find_map(|child| child.find(span_id))
+    }
+
+    fn render(&self, depth: usize, orphan_parent: Option<u64>, output: &mut String) {
+        let _ = write!(output, "{}{}", "  ".repeat(depth), self.span.operation_name);
+        if let Some(span_type) = self.span.string_tags.get(tags::SPAN_TYPE) {
+            let _ = write!(output, " [{}]", span_type);
+        }
+        if self.open {
+            output.push_str(" open");
+        } else {
+            let duration = self.span.finish_time.duration_since(self.span.start_time).unwrap_or_default();
+            let _ = write!(output, " {:?}", duration);
+        }
+        for key in TREE_TAGS {
+            if let Some(value) = self.span.tag(key) {
+                let _ = write!(output, " {}={}", key, value);
+            }
+        }
+        if let Some(parent_id) = orphan_parent {
+            let _ = write!(output, " (orphan, parent {} not found)", parent_id);
+        }
+        output.push('\n');
+        for child in &self.children {
+            child.render(depth + 1, None, output);
+        }
+    }
+}
+
+// Tags shown next to each span in the rendered tree
+static TREE_TAGS: [&str; 7] = [
+    tags::TEST_MODULE,
+    tags::TEST_SUITE,
+    tags::TEST_NAME,
+    tags::TEST_STATUS,
+    tags::TEST_SKIP_REASON,
+    tags::ERROR_TYPE,
+    tags::ERROR_MESSAGE,
+];
+
+// Hierarchy of the mock spans: session > module > suite > test > custom spans.
+// Sessions, modules, suites and tests are root spans linked by their `test_*_id` tags.
+#[derive(Debug, Clone, Default)]
+pub struct SpanTree {
+    roots: Vec<SpanNode>,
+    orphans: Vec<(u64, u64)>,
+    cycles: Vec<Vec<u64>>,
+}
+
+impl SpanTree {
+    // Tree of the finished and open spans of the mock tracer
+    #[allow(dead_code)]
+    pub fn capture() -> Self {
+        Self::from_spans(MockTracer::get_finished_spans(), MockTracer::get_open_spans())
+    }
+
+    #[allow(dead_code)]
+    pub fn from_spans(finished_spans: Vec<MockSpan>, open_spans: Vec<MockSpan>) -> Self {
+        let mut spans: HashMap<u64, (MockSpan, bool)> = HashMap::new();
+        for span in finished_spans {
+            spans.insert(span.span_id, (span, false));
+        }
+        for span in open_spans {
+            spans.entry(span.span_id).or_insert((span, true));
+        }
+
+        let parents: HashMap<u64, u64> = spans.values().filter_map(|(span, _)| Some((span.span_id, parent_of(span)?))).collect();
+        let mut children: HashMap<u64, Vec<u64>> = HashMap::new();
+        let mut tree = SpanTree::default();
+        let mut roots = Vec::new();
+        for (span_id, (span, _)) in &spans {
+            match parents.get(span_id) {
+                Some(parent_id) if spans.contains_key(parent_id) => children.entry(*parent_id).or_default().push(*span_id),
+                Some(parent_id) => {
+                    tree.orphans.push((*span_id, *parent_id));
+                    roots.push(span.span_id);
+                }
+                None => roots.push(span.span_id),
+            }
+        }
+        tree.orphans.sort_unstable();
+        tree.cycles = find_cycles(&parents);
+
+        let by_start = |ids: &mut Vec<u64>| ids.sort_by_key(|id| (spans[id].0.start_time, *id));
+        by_start(&mut roots);
+        for ids in children.values_mut() {
+            by_start(ids);
+        }
+        // Spans in a cycle aren't reachable from any root, the first span of each cycle is used as root
+        roots.extend(tree.cycles.iter().map(|cycle| cycle[0]));
+
+        let mut visited = HashSet::new();
+        tree.roots = roots.iter().filter_map(|id| build_node(*id, &spans, &children, &mut visited)).collect();
+        tree
+    }
+
+    #[allow(dead_code)]
+    pub fn roots(&self) -> &[SpanNode] {
+        &self.roots
+    }
+
+    #[allow(dead_code)]
+    pub fn find(&self, span_id: u64) -> Option<&SpanNode> {
+        self.roots.iter().find_map(|root| root.find(span_id))
+    }
+
+    // Spans whose parent wasn't found, as (span id, parent id)
+    #[allow(dead_code)]
+    pub fn orphans(&self) -> &[(u64, u64)] {
+        &self.orphans
+    }
+
+    // Span ids of each parent cycle, starting from the smallest one
+    #[allow(dead_code)]
+    pub fn cycles(&self) -> &[Vec<u64>] {
+        &self.cycles
+    }
+}
+
+impl Display for SpanTree {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let mut output = String::new();
+        for root in &self.roots {
+            let orphan_parent = self.orphans.iter().find(|(span_id, _)| *span_id == root.span.span_id).map(|(_, parent_id)| *parent_id);
+            root.render(0, orphan_parent, &mut output);
+        }
+        for cycle in &self.cycles {
+            let ids: Vec<String> = cycle.iter().map(|id| id.to_string()).collect();
+            let _ = writeln!(output, "cycle: {} -> {}", ids.join(" -> "), cycle[0]);
+        }
+        f.write_str(&output)
+    }
+}
+
+// Helper: parent span id, or the id of the session, module or suite owning the span
+fn parent_of(span: &MockSpan) -> Option<u64> {
+    if span.parent_span_id != 0 {
+        return Some(span.parent_span_id);
+    }
+    let owner_tags: &[&str] = match span.kind() {
+        SpanKind::Test => &[tags::TEST_SUITE_ID, tags::TEST_MODULE_ID, tags::TEST_SESSION_ID],
+        SpanKind::Suite => &[tags::TEST_MODULE_ID, tags::TEST_SESSION_ID],
+        SpanKind::Module => &[tags::TEST_SESSION_ID],
+        SpanKind::Session | SpanKind::Custom => &[],
+    };
+    owner_tags.iter().find_map(|key| {
+        let id = match span.number_tags.get(*key) {
+            Some(id) => *id as u64,
+            None => span.string_tags.get(*key)?.parse().ok()?,
+        };
+        (id != 0).then_some(id)
+    })
+}
+
+fn find_cycles(parents: &HashMap<u64, u64>) -> Vec<Vec<u64>> {
+    let mut cycles = Vec::new();
+    let mut checked = HashSet::new();
+    let mut span_ids: Vec<u64> = parents.keys().copied().collect();
+    span_ids.sort_unstable();
+    for span_id in span_ids {
+        let mut path = Vec::new();
+        let mut current = Some(span_id);
+        while let Some(id) = current {
+            if checked.contains(&id) {
+                break;
+            }
+            if let Some(idx) = path.iter().position(|path_id| *path_id == id) {
+                let mut cycle: Vec<u64> = path[idx..].to_vec();
+                let min_idx = cycle.iter().enumerate().min_by_key(|(_, id)| **id).map(|(idx, _)| idx).unwrap_or(0);
+                cycle.rotate_left(min_idx);
+                cycles.push(cycle);
+                break;
+            }
+            path.push(id);
+            current = parents.get(&id).copied();
+        }
+        checked.extend(path);
+    }
+    cycles
+}
+
+fn build_node(
+    span_id: u64,
+    spans: &HashMap<u64, (MockSpan, bool)>,
+    children: &HashMap<u64, Vec<u64>>,
+    visited: &mut HashSet<u64>,
+) -> Option<SpanNode> {
+    if !visited.insert(span_id) {
+        return None;
+    }
+    let (span, open) = spans.get(&span_id)?;
+    let child_nodes = children
+        .get(&span_id)
+        .map(|ids| ids.iter().filter_map(|id| build_node(*id, spans, children, visited)).collect())
+        .unwrap_or_default();
+    Some(SpanNode { span: span.clone(), open: *open, children: child_nodes })
+}
+
+/********************************
+    Snapshots
+*********************************/
+
+pub static UPDATE_SNAPSHOTS_ENV: &str = "DD_TEST_OPTIMIZATION_UPDATE_SNAPSHOTS";
+
+// Tags holding span ids, remapped like the span ids
+static ID_TAGS: [&str; 3] = [tags::TEST_SESSION_ID, tags::TEST_MODULE_ID, tags::TEST_SUITE_ID];
+
+// Tags changing between runs and machines, a trailing `.` matches every tag with that prefix
+static VOLATILE_TAGS: [&str; 11] = [
+    "os.", "runtime.", "ci.", "git.", "host", "_dd.", "process_id", "runtime-id", "library_version", tags::TEST_COMMAND, tags::ERROR_STACK,
+];
+
+static REDACTED: &str = "[redacted]";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SnapshotTimestamps {
+    // Start times and durations are not written
+    #[default]
+    Redact,
+    // Start times are written relative to the first span, and durations in milliseconds
+    Relative,
+}
+
+// Normalized text form of mock spans to compare against golden files:
+// spans are sorted by start time and ids are replaced by sequential numbers
+#[derive(Debug, Clone)]
+pub struct SpanSnapshot {
+    spans: Vec<MockSpan>,
+    timestamps: SnapshotTimestamps,
+    redacted_tags: Vec<String>,
+}
+
+impl SpanSnapshot {
+    #[allow(dead_code)]
+    pub fn new(spans: impl IntoIterator<Item = MockSpan>) -> Self {
+        let mut spans: Vec<MockSpan> = spans.into_iter().collect();
+        spans.sort_by_key(|span| (span.start_time, span.span_id));
+        Self { spans, timestamps: SnapshotTimestamps::default(), redacted_tags: VOLATILE_TAGS.iter().map(|tag| tag.to_string()).collect() }
+    }
+
+    // Snapshot of the finished spans of the mock tracer
+    #[allow(dead_code)]
+    pub fn capture() -> Self {
+        Self::new(MockTracer::get_finished_spans())
+    }
+
+    #[allow(dead_code)]
+    pub fn with_timestamps(mut self, timestamps: SnapshotTimestamps) -> Self {
+        self.timestamps = timestamps;
+        self
+    }
+
+    // Also redacts the tag, or every tag with the prefix if it ends with `.`
+    #[allow(dead_code)]
+    pub fn redact_tag(mut self, key: impl Into<String>) -> Self {
+        self.redacted_tags.push(key.into());
+        self
+    }
+
+    // Compares the snapshot with the file, or writes it when DD_TEST_OPTIMIZATION_UPDATE_SNAPSHOTS is set.
+    // Panics with a line diff if they don't match.
+    #[allow(dead_code)]
+    pub fn assert_matches(&self, path: impl AsRef<Path>) {
+        let path = path.as_ref();
+        let actual = self.to_string();
+        if env::var(UPDATE_SNAPSHOTS_ENV).is_ok_and(|value| !value.is_empty() && value != "0") {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent).unwrap_or_else(|e| panic!("failed to create {}: {}", parent.display(), e));
+            }
+            fs::write(path, &actual).unwrap_or_else(|e| panic!("failed to write the snapshot {}: {}", path.display(), e));
+            return;
+        }
+        let expected = match fs::read_to_string(path) {
+            Ok(expected) => expected,
+            Err(e) => panic!(
+                "failed to read the snapshot {}: {}\nset {}=1 to create it, actual snapshot:\n{}",
+                path.display(),
+                e,
+                UPDATE_SNAPSHOTS_ENV,
+                actual
+            ),
+        };
+        if expected != actual {
+            panic!(
+                "snapshot {} doesn't match, set {}=1 to update it\n--- expected\n+++ actual\n{}",
+                path.display(),
+                UPDATE_SNAPSHOTS_ENV,
+                line_diff(&expected, &actual)
+            );
+        }
+    }
+
+    fn is_redacted(&self, key: &str) -> bool {
+        self.redacted_tags.iter().any(|tag| if tag.ends_with('.') { key.starts_with(tag.as_str()) } else { key == tag })
+    }
+}
+
+impl Display for SpanSnapshot {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        // Ids are numbered in order of appearance, 0 stays 0
+        let mut ids: HashMap<u64, u64> = HashMap::from([(0, 0)]);
+        for span in &self.spans {
+            let next_id = ids.len() as u64;
+            ids.entry(span.span_id).or_insert(next_id);
+        }
+        let mut remap = |id: u64| {
+            let next_id = ids.len() as u64;
+            *ids.entry(id).or_insert(next_id)
+        };
+        let first_start = self.spans.first().map(|span| span.start_time);
+
+        for span in &self.spans {
+            writeln!(f, "- span: {}", remap(span.span_id))?;
+            writeln!(f, "  trace: {}", remap(span.trace_id))?;
+            writeln!(f, "  parent: {}", remap(span.parent_span_id))?;
+            writeln!(f, "  operation: {:?}", span.operation_name)?;
+            if let (SnapshotTimestamps::Relative, Some(first_start)) = (self.timestamps, first_start) {
+                let start = span.start_time.duration_since(first_start).unwrap_or_default();
+                let duration = span.finish_time.duration_since(span.start_time).unwrap_or_default();
+                writeln!(f, "  start: {}ms", start.as_millis())?;
+                writeln!(f, "  duration: {}ms", duration.as_millis())?;
+            }
+            let mut tags: Vec<(&String, String)> = Vec::new();
+            for (key, value) in &span.string_tags {
+                let value = if self.is_redacted(key) {
+                    REDACTED.to_string()
+                } else if ID_TAGS.contains(&key.as_str()) {
+                    value.parse().map(|id| remap(id).to_string()).unwrap_or_else(|_| format!("{:?}", value))
+                } else {
+                    format!("{:?}", value)
+                };
+                tags.push((key, value));
+            }
+            for (key, value) in &span.number_tags {
+                let value = if self.is_redacted(key) {
+                    REDACTED.to_string()
+                } else if ID_TAGS.contains(&key.as_str()) {
+                    remap(*value as u64).to_string()
+                } else {
+                    value.to_string()
+                };
+                tags.push((key, value));
+            }
+            tags.sort();
+            if !tags.is_empty() {
+                writeln!(f, "  tags:")?;
+                for (key, value) in tags {
+                    writeln!(f, "    {}: {}", key, value)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// Helper: lines only in `expected` prefixed with `-`, lines only in `actual` with `+`
+fn line_diff(expected: &str, actual: &str) -> String {
+    let expected: Vec<&str> = expected.lines().collect();
+    let actual: Vec<&str> = actual.lines().collect();
+    // Longest common subsequence lengths of the suffixes
+    let mut lcs = vec![vec![0usize; actual.len() + 1]; expected.len() + 1];
+    for i in (0..expected.len()).rev() {
+        for j in (0..actual.len()).rev() {
+            lcs[i][j] = if expected[i] == actual[j] { lcs[i + 1][j + 1] + 1 } else { lcs[i + 1][j].max(lcs[i][j + 1]) };
+        }
+    }
+    let mut diff = String::new();
+    let (mut i, mut j) = (0, 0);
+    while i < expected.len() || j < actual.len() {
+        if i < expected.len() && j < actual.len() && expected[i] == actual[j] {
+            let _ = writeln!(diff, " {}", expected[i]);
+            i += 1;
+            j += 1;
+        } else if j < actual.len() && (i == expected.len() || lcs[i][j + 1] > lcs[i + 1][j]) {
+            let _ = writeln!(diff, "+{}", actual[j]);
+            j += 1;
+        } else {
+            let _ = writeln!(diff, "-{}", expected[i]);
+            i += 1;
+        }
+    }
+    diff
+}
+
+/********************************
+    Scoped MockTracer
+*********************************/
+
+// Trace ids recorded by each active scope, by scope id
+static MOCK_SCOPES: Mutex<Option<HashMap<u64, HashSet<u64>>>> = Mutex::new(None);
+static NEXT_SCOPE_ID: AtomicU64 = AtomicU64::new(1);
+
+thread_local! {
+    // Scopes active in this thread, traces started in the thread are recorded in all of them
+    static ACTIVE_SCOPES: RefCell<Vec<u64>> = const { RefCell::new(Vec::new()) };
+}
+
+// Records the trace of a session, module, suite, test or span created in this thread
+pub(crate) fn record_mock_trace(trace_id: u64) {
+    if trace_id == 0 {
+        return;
+    }
+    let scope_ids = ACTIVE_SCOPES.with(|scopes| scopes.borrow().clone());
+    if scope_ids.is_empty() {
+        return;
+    }
+    let mut scopes = MOCK_SCOPES.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(scopes) = scopes.as_mut() {
+        for scope_id in scope_ids {
+            if let Some(trace_ids) = scopes.get_mut(&scope_id) {
+                trace_ids.insert(trace_id);
+            }
+        }
+    }
+}
+
+// Spans hidden from the unscoped queries by a `MockTracer::reset` called while scopes were active
+static HIDDEN_SPANS: Mutex<Option<HashSet<u64>>> = Mutex::new(None);
+
+pub(crate) fn has_mock_scopes() -> bool {
+    MOCK_SCOPES.lock().unwrap_or_else(|e| e.into_inner()).as_ref().is_some_and(|scopes| !scopes.is_empty())
+}
+
+// Reset while other tests record into their scopes: the spans are hidden from the unscoped queries and the
+// scopes of this thread forget their traces, the scopes of the other threads keep seeing them
+pub(crate) fn reset_scoped(span_ids: impl IntoIterator<Item = u64>) {
+    HIDDEN_SPANS.lock().unwrap_or_else(|e| e.into_inner()).get_or_insert_with(HashSet::new).extend(span_ids);
+    let scope_ids = ACTIVE_SCOPES.with(|scopes| scopes.borrow().clone());
+    if let Some(scopes) = MOCK_SCOPES.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+        for scope_id in scope_ids {
+            if let Some(trace_ids) = scopes.get_mut(&scope_id) {
+                trace_ids.clear();
+            }
+        }
+    }
+}
+
+pub(crate) fn clear_hidden_spans() {
+    *HIDDEN_SPANS.lock().unwrap_or_else(|e| e.into_inner()) = None;
+}
+
+pub(crate) fn visible_spans(mut spans: Vec<MockSpan>) -> Vec<MockSpan> {
+    if let Some(hidden) = HIDDEN_SPANS.lock().unwrap_or_else(|e| e.into_inner()).as_ref() {
+        spans.retain(|span| !hidden.contains(&span.span_id));
+    }
+    spans
+}
+
+// Only sees the spans of the traces started in this thread while the guard is alive,
+// including their child spans created in other threads. Lets tests running in parallel
+// assert on their own spans without calling `MockTracer::reset`.
+#[derive(Debug)]
+pub struct MockTracerScope {
+    scope_id: u64,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl MockTracer {
+    #[allow(dead_code)]
+    pub fn scoped() -> MockTracerScope {
+        let scope_id = NEXT_SCOPE_ID.fetch_add(1, Ordering::Relaxed);
+        MOCK_SCOPES.lock().unwrap_or_else(|e| e.into_inner()).get_or_insert_with(HashMap::new).insert(scope_id, HashSet::new());
+        ACTIVE_SCOPES.with(|scopes| scopes.borrow_mut().push(scope_id));
+        MockTracerScope { scope_id, _not_send: PhantomData }
+    }
+}
+
+impl MockTracerScope {
+    #[allow(dead_code)]
+    pub fn trace_ids(&self) -> HashSet<u64> {
+        let scopes = MOCK_SCOPES.lock().unwrap_or_else(|e| e.into_inner());
+        scopes.as_ref().and_then(|scopes| scopes.get(&self.scope_id)).cloned().unwrap_or_default()
+    }
+
+    // Adds a trace started outside of the scope thread, like a test created by a spawned thread
+    #[allow(dead_code)]
+    pub fn include_trace(&self, trace_id: u64) {
+        let mut scopes = MOCK_SCOPES.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(trace_ids) = scopes.as_mut().and_then(|scopes| scopes.get_mut(&self.scope_id)) {
+            trace_ids.insert(trace_id);
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn get_finished_spans(&self) -> Vec<MockSpan> {
+        self.filter(MockTracer::all_finished_spans())
+    }
+
+    #[allow(dead_code)]
+    pub fn get_open_spans(&self) -> Vec<MockSpan> {
+        self.filter(MockTracer::all_open_spans())
+    }
+
+    #[allow(dead_code)]
+    pub fn query(&self) -> SpanQuery {
+        SpanQuery::new(self.get_finished_spans())
+    }
+
+    #[allow(dead_code)]
+    pub fn query_open(&self) -> SpanQuery {
+        SpanQuery::new(self.get_open_spans())
+    }
+
+    #[allow(dead_code)]
+    pub fn span_tree(&self) -> SpanTree {
+        SpanTree::from_spans(self.get_finished_spans(), self.get_open_spans())
+    }
+
+    #[allow(dead_code)]
+    pub fn snapshot(&self) -> SpanSnapshot {
+        SpanSnapshot::new(self.get_finished_spans())
+    }
+
+    fn filter(&self, mut spans: Vec<MockSpan>) -> Vec<MockSpan> {
+        let trace_ids = self.trace_ids();
+        spans.retain(|span| trace_ids.contains(&span.trace_id));
+        spans
+    }
+}
+
+impl Drop for MockTracerScope {
+    fn drop(&mut self) {
+        ACTIVE_SCOPES.with(|scopes| scopes.borrow_mut().retain(|scope_id| *scope_id != self.scope_id));
+        if let Some(scopes) = MOCK_SCOPES.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+            scopes.remove(&self.scope_id);
+        }
+    }
+}
+
+/********************************
+    Waiting and leaked spans
+*********************************/
+
+static WAIT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+impl MockTracer {
+    // Polls the finished spans until one matches, for spans closed by async code after the test body returns
+    #[allow(dead_code)]
+    pub fn wait_for_finished(predicate: impl Fn(&MockSpan) -> bool, timeout: Duration) -> Option<MockSpan> {
+        wait_for(|| Self::get_finished_spans().into_iter().find(|span| predicate(span)), timeout)
+    }
+
+    // Open spans except the sessions, with the test owning them and their age
+    #[allow(dead_code)]
+    pub fn leak_report() -> LeakReport {
+        LeakReport::from_spans(Self::get_finished_spans(), Self::get_open_spans())
+    }
+}
+
+impl MockTracerScope {
+    #[allow(dead_code)]
+    pub fn wait_for_finished(&self, predicate: impl Fn(&MockSpan) -> bool, timeout: Duration) -> Option<MockSpan> {
+        wait_for(|| self.get_finished_spans().into_iter().find(|span| predicate(span)), timeout)
+    }
+
+    #[allow(dead_code)]
+    pub fn leak_report(&self) -> LeakReport {
+        LeakReport::from_spans(self.get_finished_spans(), self.get_open_spans())
+    }
+}
+
+fn wait_for<T>(poll: impl Fn() -> Option<T>, timeout: Duration) -> Option<T> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(value) = poll() {
+            return Some(value);
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return None;
+        }
+        thread::sleep(WAIT_POLL_INTERVAL.min(deadline - now));
+    }
+}
+
+// What to do with the spans still open when the session is closed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LeakPolicy {
+    #[default]
+    Ignore,
+    // Reports the leaks as a warning of the `tracing` or `log` facade, or on stderr without those features
+    Report,
+    // Reports the leaks and closes the session with exit code 1
+    Fail,
+}
+
+#[derive(Debug, Clone)]
+pub struct LeakedSpan {
+    pub span_id: u64,
+    pub operation_name: String,
+    // Name of the test the span belongs to, if any
+    pub test_name: Option<String>,
+    pub age: Duration,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct LeakReport {
+    spans: Vec<LeakedSpan>,
+}
+
+impl LeakReport {
+    #[allow(dead_code)]
+    pub fn from_spans(finished_spans: Vec<MockSpan>, open_spans: Vec<MockSpan>) -> Self {
+        let now = SystemTime::now();
+        let mut by_id: HashMap<u64, &MockSpan> = finished_spans.iter().map(|span| (span.span_id, span)).collect();
+        by_id.extend(open_spans.iter().map(|span| (span.span_id, span)));
+        let mut spans: Vec<LeakedSpan> = open_spans
+            .iter()
+            .filter(|span| !is_session_span(span))
+            .map(|span| LeakedSpan {
+                span_id: span.span_id,
+                operation_name: span.operation_name.clone(),
+                test_name: owning_test(span, &by_id),
+                age: now.duration_since(span.start_time).unwrap_or_default(),
+            })
+            .collect();
+        // Oldest first
+        spans.sort_by(|a, b| b.age.cmp(&a.age).then(a.span_id.cmp(&b.span_id)));
+        Self { spans }
+    }
+
+    #[allow(dead_code)]
+    pub fn spans(&self) -> &[LeakedSpan] {
+        &self.spans
+    }
+
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.spans.is_empty()
+    }
+}
+
+impl Display for LeakReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} spans still open:", self.spans.len())?;
+        for span in &self.spans {
+            write!(f, "  - `{}` ({})", span.operation_name, span.span_id)?;
+            if let Some(test_name) = &span.test_name {
+                write!(f, " in test `{}`", test_name)?;
+            }
+            writeln!(f, ", open for {:?}", span.age)?;
+        }
+        Ok(())
+    }
+}
+
+// Helper: name of the closest test span up the parent chain
+fn owning_test(span: &MockSpan, by_id: &HashMap<u64, &MockSpan>) -> Option<String> {
+    let mut current = span;
+    // Bounded walk, the chain may contain a cycle
+    for _ in 0..by_id.len() + 1 {
+        if current.kind() == SpanKind::Test {
+            return current.test_name().map(str::to_string);
+        }
+        if current.parent_span_id == 0 {
+            return None;
+        }
+        current = by_id.get(&current.parent_span_id)?;
+    }
+    None
+}
@@ -0,0 +1,202 @@
+/********************************
+    Test naming
+*********************************/
+
+// Cargo target a test is compiled in
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TestTarget {
+    // Unit tests of the library or of a binary, in `src/`
+    Unit,
+    // Integration test target, like `api` for `tests/api.rs`
+    Integration(String),
+    // Doc tests, run by `cargo test --doc`
+    Doc,
+    // Bench target, like `parser` for `benches/parser.rs`
+    Bench(String),
+}
+
+// How test targets other than unit tests are grouped into modules
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ModuleNaming {
+    // One module per target: `my_crate/tests/api`, `my_crate/doc`, `my_crate/benches/parser`
+    #[default]
+    Target,
+    // The target shares the module of the unit tests, named after the crate
+    Crate,
+}
+
+// How suites are named from `module_path!()`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SuiteNaming {
+    // Full module path, like `my_crate::parser::tests`
+    #[default]
+    ModulePath,
+    // Module path without the crate or target root, like `parser::tests` (the names shown by `cargo test`),
+    // tests at the root use the crate or target name
+    RelativeModulePath,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TestNames {
+    pub module: String,
+    pub suite: String,
+    pub test: String,
+}
+
+// Derives module, suite and test names from the crate name, `module_path!()` and the test function name,
+// so tests reported by different crates and teams are named the same way
+#[derive(Debug, Clone)]
+pub struct NamingStrategy {
+    crate_name: String,
+    integration_tests: ModuleNaming,
+    doc_tests: ModuleNaming,
+    benches: ModuleNaming,
+    suites: SuiteNaming,
+}
+
+impl NamingStrategy {
+    // Package names are normalized like rustc does, `my-crate` becomes `my_crate`
+    #[allow(dead_code)]
+    pub fn new(crate_name: impl AsRef<str>) -> Self {
+        Self {
+            crate_name: crate_name.as_ref().replace('-', "_"),
+            integration_tests: ModuleNaming::default(),
+            doc_tests: ModuleNaming::default(),
+            benches: ModuleNaming::default(),
+            suites: SuiteNaming::default(),
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn with_integration_tests(mut self, naming: ModuleNaming) -> Self {
+        self.integration_tests = naming;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn with_doc_tests(mut self, naming: ModuleNaming) -> Self {
+        self.doc_tests = naming;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn with_benches(mut self, naming: ModuleNaming) -> Self {
+        self.benches = naming;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn with_suites(mut self, naming: SuiteNaming) -> Self {
+        self.suites = naming;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn crate_name(&self) -> &str {
+        &self.crate_name
+    }
+
+    #[allow(dead_code)]
+    pub fn module_name(&self, target: &TestTarget) -> String {
+        let (naming, suffix) = match target {
+            TestTarget::Unit => return self.crate_name.clone(),
+            TestTarget::Integration(name) => (self.integration_tests, format!("tests/{}", name)),
+            TestTarget::Doc => (self.doc_tests, "doc".to_string()),
+            TestTarget::Bench(name) => (self.benches, format!("benches/{}", name)),
+        };
+        match naming {
+            ModuleNaming::Target => format!("{}/{}", self.crate_name, suffix),
+            ModuleNaming::Crate => self.crate_name.clone(),
+        }
+    }
+
+    // Suite for a `module_path!()`, for doc tests the path of the documented item's parent
+    #[allow(dead_code)]
+    pub fn suite_name(&self, module_path: impl AsRef<str>) -> String {
+        let module_path = module_path.as_ref().trim_matches(':');
+        let (root, relative) = module_path.split_once("::").unwrap_or((module_path, ""));
+        let root = if root.is_empty() { self.crate_name.as_str() } else { root };
+        if relative.is_empty() {
+            return root.to_string();
+        }
+        match self.suites {
+            SuiteNaming::ModulePath => module_path.to_string(),
+            SuiteNaming::RelativeModulePath => relative.to_string(),
+        }
+    }
+
+    // Names for a test function, like `strategy.names(&TestTarget::Unit, module_path!(), "it_works")`.
+    // For a libtest path like `parser::tests::it_works` the test is named `it_works` and `parser::tests` goes to the
+    // suite, so tests with the same function name in different modules don't collide.
+    #[allow(dead_code)]
+    pub fn names(&self, target: &TestTarget, module_path: impl AsRef<str>, test_name: impl AsRef<str>) -> TestNames {
+        let test_name = test_name.as_ref();
+        let (test_path, test) = test_name.rsplit_once("::").unwrap_or(("", test_name));
+        let module_path = module_path.as_ref().trim_matches(':');
+        let module_path = if module_path.is_empty() { self.crate_name.as_str() } else { module_path };
+        // libtest paths are relative to the crate root, `module_path!()` may already end with them
+        let relative = module_path.split_once("::").map_or("", |(_, relative)| relative);
+        let suite = if test_path.is_empty() || relative == test_path {
+            self.suite_name(module_path)
+        } else {
+            self.suite_name(format!("{}::{}", module_path, test_path))
+        };
+        TestNames {
+            module: self.module_name(target),
+            suite,
+            test: test.to_string(),
+        }
+    }
+}
+
+/********************************
+    Doc tests
+*********************************/
+
+// Framework name of the modules created for doc tests, see `TestSession::create_doc_test_module`
+pub static DOC_TEST_FRAMEWORK: &str = "rustdoc";
+
+// Name of a doc test as reported by libtest, like `src/lib.rs - parser::Parser::parse (line 42)`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DocTestName {
+    // Source file, relative to the package root
+    pub file: String,
+    // Path of the documented item, empty for crate level docs
+    pub item_path: String,
+    // Line of the code block in `file`
+    pub line: i32,
+}
+
+impl DocTestName {
+    // Parses libtest names, including the ` - compile fail` like suffixes added by rustdoc
+    #[allow(dead_code)]
+    pub fn parse(name: impl AsRef<str>) -> Option<Self> {
+        let (file, rest) = name.as_ref().trim().split_once(" - ")?;
+        let line_start = rest.rfind("(line ")?;
+        let (line, suffix) = rest[line_start + "(line ".len()..].split_once(')')?;
+        if file.is_empty() || !(suffix.is_empty() || suffix.starts_with(" - ")) {
+            return None;
+        }
+        Some(Self {
+            file: file.to_string(),
+            item_path: rest[..line_start].trim().to_string(),
+            line: line.parse().ok()?,
+        })
+    }
+
+    // The suite is the parent of the documented item and the test its last segment with the line of the code
+    // block, like `parse (line 42)`, so several examples of an item are different tests as in libtest.
+    // Crate level docs are reported as tests named after the crate.
+    #[allow(dead_code)]
+    pub fn names(&self, strategy: &NamingStrategy) -> TestNames {
+        let crate_name = strategy.crate_name();
+        let (parent, item) = match self.item_path.rsplit_once("::") {
+            Some((parent, item)) => (format!("{}::{}", crate_name, parent), item),
+            None if self.item_path.is_empty() => (crate_name.to_string(), crate_name),
+            None => (crate_name.to_string(), self.item_path.as_str()),
+        };
+        let mut names = strategy.names(&TestTarget::Doc, parent, item);
+        names.test = format!("{} (line {})", names.test, self.line);
+        names
+    }
+}
@@ -0,0 +1,172 @@
+use std::borrow::Cow;
+use std::path::{Component, Path, PathBuf};
+
+/********************************
+    Path normalization
+*********************************/
+
+// What to do with files outside the repository
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutsideRepositoryPolicy {
+    // The path is reported as given by the caller
+    #[default]
+    Keep,
+    // The absolute path is kept and the test or suite is tagged
+    Tag,
+    // Coverage files outside the repository are dropped and sources are not set
+    Reject,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NormalizedPath {
+    // Path relative to the repository root, using `/` as separator
+    Repository(String),
+    // Absolute path of a file outside the repository, using `/` as separator
+    Outside(String),
+}
+
+impl NormalizedPath {
+    #[allow(dead_code)]
+    pub fn as_str(&self) -> &str {
+        match self {
+            NormalizedPath::Repository(path) | NormalizedPath::Outside(path) => path,
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn is_outside(&self) -> bool {
+        matches!(self, NormalizedPath::Outside(_))
+    }
+}
+
+// Makes the paths reported for coverage, sources and stack traces relative to the repository root
+#[derive(Debug, Clone)]
+pub struct PathNormalizer {
+    repository_root: PathBuf,
+    working_directory: PathBuf,
+    policy: OutsideRepositoryPolicy,
+}
+
+impl PathNormalizer {
+    #[allow(dead_code)]
+    pub fn new(repository_root: impl AsRef<Path>, working_directory: impl AsRef<Path>) -> Self {
+        Self {
+            repository_root: lexical_normalize(repository_root.as_ref()),
+            working_directory: lexical_normalize(working_directory.as_ref()),
+            policy: OutsideRepositoryPolicy::default(),
+        }
+    }
+
+    // Uses the closest folder containing `.git` as repository root, or the working directory itself
+    #[allow(dead_code)]
+    pub fn discover(working_directory: impl AsRef<Path>) -> Self {
+        let working_directory = lexical_normalize(working_directory.as_ref());
+        let repository_root = working_directory
+            .ancestors()
+            .find(|folder| folder.join(".git").exists())
+            .unwrap_or(&working_directory)
+            .to_path_buf();
+        Self::new(repository_root, working_directory)
+    }
+
+    #[allow(dead_code)]
+    pub fn with_policy(mut self, policy: OutsideRepositoryPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn policy(&self) -> OutsideRepositoryPolicy {
+        self.policy
+    }
+
+    #[allow(dead_code)]
+    pub fn repository_root(&self) -> &Path {
+        &self.repository_root
+    }
+
+    #[allow(dead_code)]
+    pub fn working_directory(&self) -> &Path {
+        &self.working_directory
+    }
+
+    // Relative paths are resolved from the working directory
+    #[allow(dead_code)]
+    pub fn normalize(&self, path: impl AsRef<str>) -> NormalizedPath {
+        let path = path.as_ref();
+        let unified = if cfg!(windows) { Cow::Borrowed(path) } else { Cow::Owned(path.replace('\\', "/")) };
+        let path = Path::new(unified.as_ref());
+        let absolute_path = if path.is_absolute() {
+            lexical_normalize(path)
+        } else {
+            lexical_normalize(&self.working_directory.join(path))
+        };
+        match absolute_path.strip_prefix(&self.repository_root) {
+            Ok(relative_path) => NormalizedPath::Repository(to_slash(relative_path)),
+            Err(_) => NormalizedPath::Outside(to_slash(&absolute_path)),
+        }
+    }
+
+    // Rewrites the paths inside the repository found in a stack trace as relative paths. Only the paths
+    // starting the trace or following whitespace, `(`, quotes or `=` are rewritten, so a root like `/src`
+    // doesn't match inside other paths.
+    #[allow(dead_code)]
+    pub fn normalize_stacktrace<'a>(&self, stacktrace: &'a str) -> Cow<'a, str> {
+        let root = self.repository_root.to_string_lossy();
+        let mut prefixes = vec![format!("{}/", root.replace('\\', "/"))];
+        if cfg!(windows) {
+            prefixes.push(format!("{}\\", root.replace('/', "\\")));
+        }
+        let mut result = String::new();
+        let mut copied = 0;
+        let mut search = 0;
+        while let Some((idx, prefix)) = prefixes
+            .iter()
+            .filter_map(|prefix| stacktrace[search..].find(prefix.as_str()).map(|idx| (search + idx, prefix)))
+            .min_by_key(|(idx, _)| *idx)
+        {
+            let starts_path = stacktrace[..idx]
+                .chars()
+                .next_back()
+                .is_none_or(|c| c.is_whitespace() || matches!(c, '(' | '\'' | '"' | '='));
+            if !starts_path {
+                // Prefixes start with an ASCII character, so the next byte is a char boundary
+                search = idx + 1;
+                continue;
+            }
+            result.push_str(&stacktrace[copied..idx]);
+            let path_start = idx + prefix.len();
+            // The path ends at the first whitespace, use `/` as separator inside it
+            let path_end = stacktrace[path_start..].find(char::is_whitespace).map_or(stacktrace.len(), |len| path_start + len);
+            result.push_str(&stacktrace[path_start..path_end].replace('\\', "/"));
+            copied = path_end;
+            search = path_end;
+        }
+        if copied == 0 {
+            return Cow::Borrowed(stacktrace);
+        }
+        result.push_str(&stacktrace[copied..]);
+        Cow::Owned(result)
+    }
+}
+
+// Resolves `.` and `..` without touching the file system
+fn lexical_normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !normalized.pop() && !normalized.has_root() {
+                    normalized.push(component);
+                }
+            }
+            _ => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+fn to_slash(path: &Path) -> String {
+    path.to_string_lossy().replace('\\', "/")
+}
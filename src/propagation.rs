@@ -0,0 +1,124 @@
+use std::env;
+use std::fmt;
+
+pub static TRACEPARENT_HEADER: &str = "traceparent";
+pub static TRACESTATE_HEADER: &str = "tracestate";
+pub static TRACEPARENT_ENV: &str = "TRACEPARENT";
+pub static TRACESTATE_ENV: &str = "TRACESTATE";
+
+/********************************
+    W3C trace context
+*********************************/
+
+// Trace context propagated to child processes and HTTP calls, following https://www.w3.org/TR/trace-context/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceContext {
+    pub trace_id: u128,
+    pub parent_id: u64,
+    pub sampled: bool,
+    pub tracestate: Option<String>,
+}
+
+impl TraceContext {
+    // Context of a local test or span, `tracestate` carries the parent id for Datadog
+    pub(crate) fn local(trace_id: u64, parent_id: u64) -> Self {
+        Self {
+            trace_id: trace_id as u128,
+            parent_id,
+            sampled: true,
+            tracestate: Some(format!("dd=s:1;p:{:016x}", parent_id)),
+        }
+    }
+
+    // Lower 64 bits of the trace id, the ones used by the tracer
+    #[allow(dead_code)]
+    pub fn trace_id_low(&self) -> u64 {
+        self.trace_id as u64
+    }
+
+    #[allow(dead_code)]
+    pub fn to_traceparent(&self) -> String {
+        format!("00-{:032x}-{:016x}-{:02x}", self.trace_id, self.parent_id, self.sampled as u8)
+    }
+
+    // Parses a `traceparent` value, rejecting invalid and all-zero ids
+    #[allow(dead_code)]
+    pub fn from_traceparent(traceparent: &str) -> Option<Self> {
+        let mut parts = traceparent.trim().split('-');
+        let version = parts.next()?;
+        let trace_id = parts.next()?;
+        let parent_id = parts.next()?;
+        let flags = parts.next()?;
+        // Version 00 has exactly four parts, later versions may append more
+        if version.len() != 2 || version.eq_ignore_ascii_case("ff") || (version == "00" && parts.next().is_some()) {
+            return None;
+        }
+        if trace_id.len() != 32 || parent_id.len() != 16 || flags.len() != 2 {
+            return None;
+        }
+        u8::from_str_radix(version, 16).ok()?;
+        let trace_id = u128::from_str_radix(trace_id, 16).ok()?;
+        let parent_id = u64::from_str_radix(parent_id, 16).ok()?;
+        let flags = u8::from_str_radix(flags, 16).ok()?;
+        if trace_id == 0 || parent_id == 0 {
+            return None;
+        }
+        Some(Self { trace_id, parent_id, sampled: flags & 1 == 1, tracestate: None })
+    }
+
+    #[allow(dead_code)]
+    pub fn with_tracestate(mut self, tracestate: impl Into<String>) -> Self {
+        let tracestate = tracestate.into();
+        self.tracestate = if tracestate.trim().is_empty() { None } else { Some(tracestate) };
+        self
+    }
+
+    // Header names and values to add to an HTTP request
+    #[allow(dead_code)]
+    pub fn to_headers(&self) -> Vec<(&'static str, String)> {
+        let mut headers = vec![(TRACEPARENT_HEADER, self.to_traceparent())];
+        if let Some(tracestate) = &self.tracestate {
+            headers.push((TRACESTATE_HEADER, tracestate.clone()));
+        }
+        headers
+    }
+
+    // Reads the context from HTTP headers, header names are case insensitive
+    #[allow(dead_code)]
+    pub fn from_headers<'a>(headers: impl IntoIterator<Item = (&'a str, &'a str)>) -> Option<Self> {
+        let mut traceparent = None;
+        let mut tracestate = Vec::new();
+        for (name, value) in headers {
+            if name.eq_ignore_ascii_case(TRACEPARENT_HEADER) {
+                traceparent = Some(value);
+            } else if name.eq_ignore_ascii_case(TRACESTATE_HEADER) {
+                // Multiple tracestate headers are combined
+                tracestate.push(value);
+            }
+        }
+        Some(Self::from_traceparent(traceparent?)?.with_tracestate(tracestate.join(",")))
+    }
+
+    // Environment variables to set on a child process, like `Command::envs`
+    #[allow(dead_code)]
+    pub fn to_env_vars(&self) -> Vec<(&'static str, String)> {
+        let mut vars = vec![(TRACEPARENT_ENV, self.to_traceparent())];
+        if let Some(tracestate) = &self.tracestate {
+            vars.push((TRACESTATE_ENV, tracestate.clone()));
+        }
+        vars
+    }
+
+    // Reads the context set by the parent process, if any
+    #[allow(dead_code)]
+    pub fn from_env() -> Option<Self> {
+        let context = Self::from_traceparent(&env::var(TRACEPARENT_ENV).ok()?)?;
+        Some(context.with_tracestate(env::var(TRACESTATE_ENV).unwrap_or_default()))
+    }
+}
+
+impl fmt::Display for TraceContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_traceparent())
+    }
+}
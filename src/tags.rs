@@ -0,0 +1,212 @@
+use crate::test_optimization::{Span, Test, TestModule, TestSession, TestSuite};
+
+/********************************
+    Tags
+*********************************/
+
+// Names of the standard test optimization tags, and of the tags set by this library
+
+pub static SPAN_TYPE: &str = "span.type";
+
+// Values of `span.type`
+pub static SPAN_TYPE_SESSION: &str = "test_session_end";
+pub static SPAN_TYPE_MODULE: &str = "test_module_end";
+pub static SPAN_TYPE_SUITE: &str = "test_suite_end";
+pub static SPAN_TYPE_TEST: &str = "test";
+
+pub static TEST_SESSION_ID: &str = "test_session_id";
+pub static TEST_MODULE_ID: &str = "test_module_id";
+pub static TEST_SUITE_ID: &str = "test_suite_id";
+
+pub static TEST_MODULE: &str = "test.module";
+pub static TEST_SUITE: &str = "test.suite";
+pub static TEST_NAME: &str = "test.name";
+pub static TEST_TYPE: &str = "test.type";
+pub static TEST_FRAMEWORK: &str = "test.framework";
+pub static TEST_FRAMEWORK_VERSION: &str = "test.framework_version";
+pub static TEST_COMMAND: &str = "test.command";
+pub static TEST_STATUS: &str = "test.status";
+pub static TEST_SKIP_REASON: &str = "test.skip_reason";
+pub static TEST_SOURCE_FILE: &str = "test.source.file";
+pub static TEST_SOURCE_START: &str = "test.source.start";
+pub static TEST_SOURCE_END: &str = "test.source.end";
+pub static TEST_CODEOWNERS: &str = "test.codeowners";
+pub static TEST_PARAMETERS: &str = "test.parameters";
+pub static TEST_IS_NEW: &str = "test.is_new";
+pub static TEST_IS_RETRY: &str = "test.is_retry";
+pub static TEST_RETRY_REASON: &str = "test.retry_reason";
+pub static TEST_ITR_UNSKIPPABLE: &str = "test.itr.unskippable";
+pub static TEST_ITR_FORCED_RUN: &str = "test.itr.forced_run";
+pub static TEST_CODE_COVERAGE_LINES_PCT: &str = "test.code_coverage.lines_pct";
+
+// Values of `test.status`
+pub static TEST_STATUS_PASS: &str = "pass";
+pub static TEST_STATUS_FAIL: &str = "fail";
+pub static TEST_STATUS_SKIP: &str = "skip";
+
+// Values of `test.retry_reason`
+pub static RETRY_REASON_EARLY_FLAKE_DETECTION: &str = "early_flake_detection";
+pub static RETRY_REASON_AUTO_TEST_RETRY: &str = "auto_test_retry";
+pub static RETRY_REASON_ATTEMPT_TO_FIX: &str = "attempt_to_fix";
+pub static RETRY_REASON_EXTERNAL: &str = "external";
+
+// Why a test is executed again, sent as `test.retry_reason`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetryReason {
+    EarlyFlakeDetection,
+    AutoTestRetry,
+    AttemptToFix,
+    External,
+}
+
+impl RetryReason {
+    #[allow(dead_code)]
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RetryReason::EarlyFlakeDetection => RETRY_REASON_EARLY_FLAKE_DETECTION,
+            RetryReason::AutoTestRetry => RETRY_REASON_AUTO_TEST_RETRY,
+            RetryReason::AttemptToFix => RETRY_REASON_ATTEMPT_TO_FIX,
+            RetryReason::External => RETRY_REASON_EXTERNAL,
+        }
+    }
+}
+
+pub static ERROR_TYPE: &str = "error.type";
+pub static ERROR_MESSAGE: &str = "error.message";
+pub static ERROR_STACK: &str = "error.stack";
+
+pub static GIT_REPOSITORY_URL: &str = "git.repository_url";
+pub static GIT_BRANCH: &str = "git.branch";
+pub static GIT_TAG: &str = "git.tag";
+pub static GIT_COMMIT_SHA: &str = "git.commit.sha";
+pub static GIT_COMMIT_MESSAGE: &str = "git.commit.message";
+pub static GIT_COMMIT_AUTHOR_NAME: &str = "git.commit.author.name";
+pub static GIT_COMMIT_AUTHOR_EMAIL: &str = "git.commit.author.email";
+pub static GIT_COMMIT_AUTHOR_DATE: &str = "git.commit.author.date";
+pub static GIT_COMMIT_COMMITTER_NAME: &str = "git.commit.committer.name";
+pub static GIT_COMMIT_COMMITTER_EMAIL: &str = "git.commit.committer.email";
+pub static GIT_COMMIT_COMMITTER_DATE: &str = "git.commit.committer.date";
+
+pub static CI_PROVIDER_NAME: &str = "ci.provider.name";
+pub static CI_PIPELINE_ID: &str = "ci.pipeline.id";
+pub static CI_PIPELINE_NAME: &str = "ci.pipeline.name";
+pub static CI_PIPELINE_NUMBER: &str = "ci.pipeline.number";
+pub static CI_PIPELINE_URL: &str = "ci.pipeline.url";
+pub static CI_JOB_NAME: &str = "ci.job.name";
+pub static CI_JOB_URL: &str = "ci.job.url";
+pub static CI_STAGE_NAME: &str = "ci.stage.name";
+pub static CI_NODE_NAME: &str = "ci.node.name";
+pub static CI_NODE_LABELS: &str = "ci.node.labels";
+pub static CI_WORKSPACE_PATH: &str = "ci.workspace_path";
+
+pub static OS_PLATFORM: &str = "os.platform";
+pub static OS_VERSION: &str = "os.version";
+pub static OS_ARCHITECTURE: &str = "os.architecture";
+
+pub static RUNTIME_NAME: &str = "runtime.name";
+pub static RUNTIME_VERSION: &str = "runtime.version";
+
+// Links to spans of other traces, as a JSON array
+
+// Tags set by this library
+pub static TEST_SOURCE_OUTSIDE_REPOSITORY: &str = "test.source.outside_repository";
+pub static TEST_COVERAGE_OUTSIDE_REPOSITORY_FILES: &str = "test.coverage.outside_repository_files";
+pub static TEST_LOGS: &str = "test.logs";
+pub static TRACE_PARENT_TRACESTATE: &str = "trace.parent.tracestate";
+
+/********************************
+    Typed setters
+*********************************/
+
+// Typed helpers for the common tags, implemented by sessions, modules, suites, tests and spans
+pub trait TagSetter {
+    fn set_string_tag(&self, key: impl AsRef<str>, value: impl AsRef<str>) -> bool;
+
+    fn set_number_tag(&self, key: impl AsRef<str>, value: f64) -> bool;
+
+    // Sets `error.type`, `error.message` and `error.stack`
+    fn set_error(&self, error_type: impl AsRef<str>, error_message: impl AsRef<str>, error_stack: impl AsRef<str>) -> bool;
+
+    // Boolean tags are sent as "true" or "false"
+    fn set_bool_tag(&self, key: impl AsRef<str>, value: bool) -> bool {
+        self.set_string_tag(key, if value { "true" } else { "false" })
+    }
+
+    // Owners are sent as a JSON array, like `["@org/team", "user@example.com"]`
+    fn set_codeowners(&self, owners: &[impl AsRef<str>]) -> bool {
+        let owners: Vec<String> = owners.iter().map(|owner| json_string(owner.as_ref())).collect();
+        self.set_string_tag(TEST_CODEOWNERS, format!("[{}]", owners.join(",")))
+    }
+
+    // Unskippable suites and tests always run, even if test impact analysis would skip them
+    fn set_unskippable(&self, unskippable: bool) -> bool {
+        self.set_bool_tag(TEST_ITR_UNSKIPPABLE, unskippable)
+    }
+
+    fn set_is_retry(&self, is_retry: bool) -> bool {
+        self.set_bool_tag(TEST_IS_RETRY, is_retry)
+    }
+
+    fn set_retry_reason(&self, retry_reason: RetryReason) -> bool {
+        self.set_string_tag(TEST_RETRY_REASON, retry_reason.as_str())
+    }
+
+    fn set_git_repository_url(&self, repository_url: impl AsRef<str>) -> bool {
+        self.set_string_tag(GIT_REPOSITORY_URL, repository_url)
+    }
+
+    fn set_git_branch(&self, branch: impl AsRef<str>) -> bool {
+        self.set_string_tag(GIT_BRANCH, branch)
+    }
+
+    fn set_git_commit_sha(&self, commit_sha: impl AsRef<str>) -> bool {
+        self.set_string_tag(GIT_COMMIT_SHA, commit_sha)
+    }
+}
+
+macro_rules! impl_tag_setter {
+    ($($entity:ty),*) => {
+        $(
+            impl TagSetter for $entity {
+                fn set_string_tag(&self, key: impl AsRef<str>, value: impl AsRef<str>) -> bool {
+                    <$entity>::set_string_tag(self, key, value)
+                }
+
+                fn set_number_tag(&self, key: impl AsRef<str>, value: f64) -> bool {
+                    <$entity>::set_number_tag(self, key, value)
+                }
+
+                // Through the native error setter, which also normalizes the stack trace paths
+                fn set_error(
+                    &self,
+                    error_type: impl AsRef<str>,
+                    error_message: impl AsRef<str>,
+                    error_stack: impl AsRef<str>,
+                ) -> bool {
+                    <$entity>::set_error_info(self, error_type, error_message, error_stack)
+                }
+            }
+        )*
+    };
+}
+
+impl_tag_setter!(TestSession, TestModule, TestSuite, Test, Span);
+
+// Helper: JSON string literal, escaping quotes, backslashes and control characters
+pub(crate) fn json_string(value: &str) -> String {
+    let mut result = String::with_capacity(value.len() + 2);
+    result.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '\r' => result.push_str("\\r"),
+            '\t' => result.push_str("\\t"),
+            c if (c as u32) < 0x20 => result.push_str(&format!("\\u{:04x}", c as u32)),
+            c => result.push(c),
+        }
+    }
+    result.push('"');
+    result
+}
@@ -0,0 +1,152 @@
+use crate::context::current_test_id;
+use log::{LevelFilter, Log, Metadata, Record, SetLoggerError};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+/********************************
+    Test logger
+*********************************/
+
+// Log lines buffered per test id, attached to the test if it fails
+static LOG_BUFFERS: Mutex<Option<HashMap<u64, LogBuffer>>> = Mutex::new(None);
+
+#[derive(Debug, Default)]
+struct LogBuffer {
+    lines: VecDeque<String>,
+    size: usize,
+    dropped: usize,
+}
+
+impl LogBuffer {
+    // Keeps the most recent lines within `max_size` bytes
+    fn push(&mut self, mut line: String, max_size: usize) {
+        if line.len() > max_size {
+            let mut end = max_size;
+            while !line.is_char_boundary(end) {
+                end -= 1;
+            }
+            line.truncate(end);
+        }
+        self.size += line.len() + 1;
+        self.lines.push_back(line);
+        while self.size > max_size + 1 {
+            match self.lines.pop_front() {
+                Some(dropped) => {
+                    self.size -= dropped.len() + 1;
+                    self.dropped += 1;
+                }
+                None => break,
+            }
+        }
+    }
+
+    fn into_string(self) -> String {
+        let mut result = String::with_capacity(self.size + 48);
+        if self.dropped > 0 {
+            result.push_str(&format!("... {} earlier log lines dropped\n", self.dropped));
+        }
+        for line in self.lines {
+            result.push_str(&line);
+            result.push('\n');
+        }
+        result.pop();
+        result
+    }
+}
+
+// Starts buffering the log lines of a new test, records are only kept for tests between creation and close
+pub(crate) fn start_test_logs(test_id: u64) {
+    let mut buffers = LOG_BUFFERS.lock().unwrap_or_else(|e| e.into_inner());
+    buffers.get_or_insert_with(HashMap::new).insert(test_id, LogBuffer::default());
+}
+
+// Removes the log lines buffered for a test
+pub(crate) fn take_test_logs(test_id: u64) -> Option<String> {
+    let mut buffers = LOG_BUFFERS.lock().unwrap_or_else(|e| e.into_inner());
+    let buffer = buffers.as_mut()?.remove(&test_id)?;
+    Some(buffer.into_string())
+}
+
+// Drops the buffers of the tests never closed, when their session ends
+pub(crate) fn clear_test_logs() {
+    *LOG_BUFFERS.lock().unwrap_or_else(|e| e.into_inner()) = None;
+}
+
+// `log` implementation buffering the records emitted while a test is entered (see `Test::enter`),
+// failed tests get them in the `test.logs` tag. Records can also be forwarded to another logger.
+pub struct TestLogger {
+    level: LevelFilter,
+    max_size: usize,
+    inner: Option<Box<dyn Log>>,
+}
+
+impl Default for TestLogger {
+    fn default() -> Self {
+        Self { level: LevelFilter::Info, max_size: 64 * 1024, inner: None }
+    }
+}
+
+impl TestLogger {
+    #[allow(dead_code)]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Most verbose level buffered, `Info` by default
+    #[allow(dead_code)]
+    pub fn with_level(mut self, level: LevelFilter) -> Self {
+        self.level = level;
+        self
+    }
+
+    // Maximum size in bytes of the logs of a test (64 KiB by default), older lines are dropped first
+    #[allow(dead_code)]
+    pub fn with_max_size(mut self, max_size: usize) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    // Logger receiving every record, like the one used outside of tests
+    #[allow(dead_code)]
+    pub fn with_inner(mut self, inner: impl Log + 'static) -> Self {
+        self.inner = Some(Box::new(inner));
+        self
+    }
+
+    // Sets this as the global logger
+    #[allow(dead_code)]
+    pub fn init(self) -> Result<(), SetLoggerError> {
+        let max_level = if self.inner.is_some() { LevelFilter::Trace } else { self.level };
+        log::set_boxed_logger(Box::new(self))?;
+        log::set_max_level(max_level);
+        Ok(())
+    }
+}
+
+impl Log for TestLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level || self.inner.as_ref().is_some_and(|inner| inner.enabled(metadata))
+    }
+
+    fn log(&self, record: &Record) {
+        if record.level() <= self.level {
+            if let Some(test_id) = current_test_id() {
+                let line = format!("{} {}: {}", record.level(), record.target(), record.args());
+                let mut buffers = LOG_BUFFERS.lock().unwrap_or_else(|e| e.into_inner());
+                // Closed tests have no buffer anymore, so late records don't create one
+                if let Some(buffer) = buffers.as_mut().and_then(|buffers| buffers.get_mut(&test_id)) {
+                    buffer.push(line, self.max_size);
+                }
+            }
+        }
+        if let Some(inner) = &self.inner {
+            inner.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(inner) = &self.inner {
+            inner.flush();
+        }
+    }
+}
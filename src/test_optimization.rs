@@ -2,11 +2,37 @@
 
 #[cfg(target_os = "windows")]
 use crate::cgo::*;
+#[cfg(feature = "cache")]
+use crate::cache::TestOptimizationCache;
+use crate::context::{self, ContextGuard};
+use crate::coverage::{
+    send_coverage_records, CoverageBatcher, CoverageBitmap, CoverageError, CoverageReport, CoverageSummary, TestCoverageRecord,
+};
+use crate::fixture::Fixture;
+#[cfg(feature = "fixture")]
+use crate::fixture::{FixtureError, FIXTURE_ENV};
 use crate::libcivisibility_bindings::*;
+use crate::mock_tracer::{clear_hidden_spans, has_mock_scopes, record_mock_trace, reset_scoped, visible_spans, LeakPolicy};
+use crate::naming::{DocTestName, NamingStrategy, TestTarget, DOC_TEST_FRAMEWORK};
+use crate::paths::{NormalizedPath, OutsideRepositoryPolicy, PathNormalizer};
+use crate::propagation::TraceContext;
+use crate::tags::{self, RetryReason, TagSetter};
+#[cfg(feature = "log")]
+use crate::test_logger::{clear_test_logs, start_test_logs, take_test_logs};
+#[cfg(feature = "cache")]
+use serde::{de::DeserializeOwned, Serialize};
 use std::alloc::{alloc, dealloc, Layout};
-use std::collections::HashMap;
+use std::any::Any;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::ffi::{c_char, CStr, CString};
-use std::ptr::null_mut;
+use std::fmt::{self, Display, Formatter};
+#[cfg(feature = "tokio")]
+use std::future::Future;
+use std::panic::{catch_unwind, resume_unwind, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::ptr::{null, null_mut};
+use std::sync::{Arc, Mutex};
 use std::thread::panicking;
 use std::time::{Duration, SystemTime};
 
@@ -22,7 +48,8 @@ fn Bool_to_bool(value: Bool) -> bool {
     value > 0
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(default))]
 pub struct Settings {
     #[allow(dead_code)]
     pub code_coverage: bool,
@@ -42,7 +69,8 @@ pub struct Settings {
     pub test_management: TestManagementSettings,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(default))]
 pub struct EfDSettings {
     #[allow(dead_code)]
     pub enabled: bool,
@@ -52,7 +80,8 @@ pub struct EfDSettings {
     pub faulty_session_threshold: i32,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(default))]
 pub struct EfdSlowTestRetriesSettings {
     #[allow(dead_code)]
     pub ten_s: i32,
@@ -64,7 +93,8 @@ pub struct EfdSlowTestRetriesSettings {
     pub five_s: i32,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(default))]
 pub struct FlakyTestRetriesSettings {
     #[allow(dead_code)]
     pub retry_count: i32,
@@ -72,7 +102,8 @@ pub struct FlakyTestRetriesSettings {
     pub total_retry_count: i32,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(default))]
 pub struct TestManagementSettings {
     #[allow(dead_code)]
     pub enabled: bool,
@@ -80,7 +111,8 @@ pub struct TestManagementSettings {
     pub attempt_to_fix_retries: i32,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(default))]
 pub struct SkippableTest {
     #[allow(dead_code)]
     pub suite_name: String,
@@ -92,7 +124,8 @@ pub struct SkippableTest {
     pub custom_configurations_json: String,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(default))]
 pub struct TestManagementTest {
     #[allow(dead_code)]
     pub module_name: String,
@@ -108,25 +141,745 @@ pub struct TestManagementTest {
     pub attempt_to_fix: bool,
 }
 
-#[derive(Debug, Clone)]
-pub struct MockSpan {
-    #[allow(dead_code)]
-    pub span_id: u64,
-    #[allow(dead_code)]
-    pub trace_id: u64,
-    #[allow(dead_code)]
-    pub parent_span_id: u64,
-    #[allow(dead_code)]
-    pub start_time: SystemTime,
-    #[allow(dead_code)]
-    pub finish_time: SystemTime,
-    #[allow(dead_code)]
-    pub operation_name: String,
-    #[allow(dead_code)]
-    pub string_tags: HashMap<String, String>,
-    #[allow(dead_code)]
-    pub number_tags: HashMap<String, f64>,
-}
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MockSpan {
+    #[allow(dead_code)]
+    pub span_id: u64,
+    #[allow(dead_code)]
+    pub trace_id: u64,
+    #[allow(dead_code)]
+    pub parent_span_id: u64,
+    #[allow(dead_code)]
+    #[cfg_attr(feature = "serde", serde(with = "unix_nanos"))]
+    pub start_time: SystemTime,
+    #[allow(dead_code)]
+    #[cfg_attr(feature = "serde", serde(with = "unix_nanos"))]
+    pub finish_time: SystemTime,
+    #[allow(dead_code)]
+    pub operation_name: String,
+    #[allow(dead_code)]
+    pub string_tags: HashMap<String, String>,
+    #[allow(dead_code)]
+    pub number_tags: HashMap<String, f64>,
+}
+
+// Serializes a SystemTime as the number of nanoseconds since the unix epoch
+#[cfg(feature = "serde")]
+mod unix_nanos {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::time::{Duration, SystemTime};
+
+    pub fn serialize<S: Serializer>(time: &SystemTime, serializer: S) -> Result<S::Ok, S::Error> {
+        let nanos = time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_err(serde::ser::Error::custom)?
+            .as_nanos();
+        serializer.serialize_u64(u64::try_from(nanos).map_err(serde::ser::Error::custom)?)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<SystemTime, D::Error> {
+        let nanos = u64::deserialize(deserializer)?;
+        Ok(SystemTime::UNIX_EPOCH + Duration::from_nanos(nanos))
+    }
+}
+
+// Serializes the test lists with sorted keys, so the same lists always give the same output
+#[cfg(feature = "serde")]
+mod sorted {
+    use super::{SkippableTest, TestManagementTest};
+    use serde::{Serialize, Serializer};
+    use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+    pub fn known_tests<S: Serializer>(
+        modules: &HashMap<String, HashMap<String, HashSet<String>>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let sorted: BTreeMap<_, BTreeMap<_, BTreeSet<_>>> = modules
+            .iter()
+            .map(|(module, suites)| (module, suites.iter().map(|(suite, tests)| (suite, tests.iter().collect())).collect()))
+            .collect();
+        sorted.serialize(serializer)
+    }
+
+    pub fn skippable_tests<S: Serializer>(
+        suites: &HashMap<String, HashMap<String, Vec<SkippableTest>>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let sorted: BTreeMap<_, BTreeMap<_, _>> = suites.iter().map(|(suite, tests)| (suite, tests.iter().collect())).collect();
+        sorted.serialize(serializer)
+    }
+
+    pub fn test_management_tests<S: Serializer>(
+        modules: &HashMap<String, HashMap<String, HashMap<String, TestManagementTest>>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let sorted: BTreeMap<_, BTreeMap<_, BTreeMap<_, _>>> = modules
+            .iter()
+            .map(|(module, suites)| (module, suites.iter().map(|(suite, tests)| (suite, tests.iter().collect())).collect()))
+            .collect();
+        sorted.serialize(serializer)
+    }
+}
+
+/********************************
+    Test lists
+*********************************/
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(transparent))]
+pub struct KnownTests {
+    #[cfg_attr(feature = "serde", serde(serialize_with = "sorted::known_tests"))]
+    modules: HashMap<String, HashMap<String, HashSet<String>>>,
+}
+
+impl KnownTests {
+    #[allow(dead_code)]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[allow(dead_code)]
+    pub fn insert(&mut self, module_name: impl Into<String>, suite_name: impl Into<String>, test_name: impl Into<String>) -> bool {
+        self.modules
+            .entry(module_name.into())
+            .or_default()
+            .entry(suite_name.into())
+            .or_default()
+            .insert(test_name.into())
+    }
+
+    #[allow(dead_code)]
+    pub fn contains(&self, module_name: impl AsRef<str>, suite_name: impl AsRef<str>, test_name: impl AsRef<str>) -> bool {
+        self.modules
+            .get(module_name.as_ref())
+            .and_then(|suites| suites.get(suite_name.as_ref()))
+            .is_some_and(|tests| tests.contains(test_name.as_ref()))
+    }
+
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.modules.is_empty()
+    }
+
+    #[allow(dead_code)]
+    pub fn len(&self) -> usize {
+        self.modules.values().flat_map(|suites| suites.values()).map(|tests| tests.len()).sum()
+    }
+
+    #[allow(dead_code)]
+    pub fn modules(&self) -> impl Iterator<Item = &str> {
+        self.modules.keys().map(String::as_str)
+    }
+
+    #[allow(dead_code)]
+    pub fn suites(&self, module_name: impl AsRef<str>) -> impl Iterator<Item = &str> {
+        self.modules.get(module_name.as_ref()).into_iter().flat_map(|suites| suites.keys().map(String::as_str))
+    }
+
+    #[allow(dead_code)]
+    pub fn tests(&self, module_name: impl AsRef<str>, suite_name: impl AsRef<str>) -> impl Iterator<Item = &str> {
+        self.modules
+            .get(module_name.as_ref())
+            .and_then(|suites| suites.get(suite_name.as_ref()))
+            .into_iter()
+            .flat_map(|tests| tests.iter().map(String::as_str))
+    }
+
+    // Iterates over (module, suite, test) tuples
+    #[allow(dead_code)]
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str, &str)> {
+        self.modules.iter().flat_map(|(module_name, suites)| {
+            suites.iter().flat_map(move |(suite_name, tests)| {
+                tests.iter().map(move |test_name| (module_name.as_str(), suite_name.as_str(), test_name.as_str()))
+            })
+        })
+    }
+
+    #[allow(dead_code)]
+    pub fn filter_module(&self, module_name: impl AsRef<str>) -> KnownTests {
+        self.iter().filter(|(module, _, _)| *module == module_name.as_ref()).collect()
+    }
+
+    #[allow(dead_code)]
+    pub fn filter_suite(&self, module_name: impl AsRef<str>, suite_name: impl AsRef<str>) -> KnownTests {
+        self.iter()
+            .filter(|(module, suite, _)| *module == module_name.as_ref() && *suite == suite_name.as_ref())
+            .collect()
+    }
+
+    #[allow(dead_code)]
+    pub fn union(&self, other: &KnownTests) -> KnownTests {
+        self.iter().chain(other.iter()).collect()
+    }
+
+    #[allow(dead_code)]
+    pub fn intersection(&self, other: &KnownTests) -> KnownTests {
+        self.iter().filter(|(module, suite, test)| other.contains(module, suite, test)).collect()
+    }
+
+    #[allow(dead_code)]
+    pub fn difference(&self, other: &KnownTests) -> KnownTests {
+        self.iter().filter(|(module, suite, test)| !other.contains(module, suite, test)).collect()
+    }
+}
+
+impl<M: Into<String>, S: Into<String>, T: Into<String>> FromIterator<(M, S, T)> for KnownTests {
+    fn from_iter<I: IntoIterator<Item = (M, S, T)>>(iter: I) -> Self {
+        let mut known_tests = KnownTests::new();
+        known_tests.extend(iter);
+        known_tests
+    }
+}
+
+impl<M: Into<String>, S: Into<String>, T: Into<String>> Extend<(M, S, T)> for KnownTests {
+    fn extend<I: IntoIterator<Item = (M, S, T)>>(&mut self, iter: I) {
+        for (module_name, suite_name, test_name) in iter {
+            self.insert(module_name, suite_name, test_name);
+        }
+    }
+}
+
+impl From<&TestManagementTests> for KnownTests {
+    fn from(value: &TestManagementTests) -> Self {
+        value.iter().map(|test| (test.module_name.as_str(), test.suite_name.as_str(), test.test_name.as_str())).collect()
+    }
+}
+
+impl Display for KnownTests {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let mut lines: Vec<_> = self.iter().collect();
+        lines.sort_unstable();
+        for (module_name, suite_name, test_name) in lines {
+            writeln!(f, "{} > {} > {}", module_name, suite_name, test_name)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(transparent))]
+pub struct SkippableTests {
+    #[cfg_attr(feature = "serde", serde(serialize_with = "sorted::skippable_tests"))]
+    suites: HashMap<String, HashMap<String, Vec<SkippableTest>>>,
+}
+
+impl SkippableTests {
+    #[allow(dead_code)]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[allow(dead_code)]
+    pub fn insert(&mut self, test: SkippableTest) {
+        self.suites
+            .entry(test.suite_name.clone())
+            .or_default()
+            .entry(test.test_name.clone())
+            .or_default()
+            .push(test);
+    }
+
+    #[allow(dead_code)]
+    pub fn contains(&self, suite_name: impl AsRef<str>, test_name: impl AsRef<str>) -> bool {
+        !self.get(suite_name, test_name).is_empty()
+    }
+
+    // Matches a test instance by name and parameters. With the `serde` feature the parameters are compared as
+    // JSON, so the key order and the number formats don't matter. The empty forms of a non-parameterized test
+    // (`""`, `{}`, `{"arguments":{},"metadata":{}}`) always match each other.
+    #[allow(dead_code)]
+    pub fn contains_parameterized(
+        &self,
+        suite_name: impl AsRef<str>,
+        test_name: impl AsRef<str>,
+        parameters: impl AsRef<str>,
+    ) -> bool {
+        let parameters = parameters.as_ref();
+        self.get(suite_name, test_name).iter().any(|test| same_parameters(&test.parameters, parameters))
+    }
+
+    #[allow(dead_code)]
+    pub fn contains_test(&self, test: &Test) -> bool {
+        self.contains_parameterized(&test.suite_name, &test.test_name, test.parameters.as_deref().unwrap_or_default())
+    }
+
+    // Returns every skippable entry (one per set of parameters) for a test
+    #[allow(dead_code)]
+    pub fn get(&self, suite_name: impl AsRef<str>, test_name: impl AsRef<str>) -> &[SkippableTest] {
+        self.suites
+            .get(suite_name.as_ref())
+            .and_then(|tests| tests.get(test_name.as_ref()))
+            .map_or(&[], Vec::as_slice)
+    }
+
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.suites.is_empty()
+    }
+
+    #[allow(dead_code)]
+    pub fn len(&self) -> usize {
+        self.suites.values().flat_map(|tests| tests.values()).map(Vec::len).sum()
+    }
+
+    #[allow(dead_code)]
+    pub fn suites(&self) -> impl Iterator<Item = &str> {
+        self.suites.keys().map(String::as_str)
+    }
+
+    #[allow(dead_code)]
+    pub fn iter(&self) -> impl Iterator<Item = &SkippableTest> {
+        self.suites.values().flat_map(|tests| tests.values()).flatten()
+    }
+
+    #[allow(dead_code)]
+    pub fn filter_suite(&self, suite_name: impl AsRef<str>) -> SkippableTests {
+        self.iter().filter(|test| test.suite_name == suite_name.as_ref()).cloned().collect()
+    }
+}
+
+impl FromIterator<SkippableTest> for SkippableTests {
+    fn from_iter<I: IntoIterator<Item = SkippableTest>>(iter: I) -> Self {
+        let mut skippable_tests = SkippableTests::new();
+        for test in iter {
+            skippable_tests.insert(test);
+        }
+        skippable_tests
+    }
+}
+
+impl Display for SkippableTests {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let mut tests: Vec<_> = self.iter().collect();
+        tests.sort_unstable_by(|a, b| {
+            (&a.suite_name, &a.test_name, &a.parameters).cmp(&(&b.suite_name, &b.test_name, &b.parameters))
+        });
+        for test in tests {
+            if test.parameters.is_empty() {
+                writeln!(f, "{} > {}", test.suite_name, test.test_name)?;
+            } else {
+                writeln!(f, "{} > {} {}", test.suite_name, test.test_name, test.parameters)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(transparent))]
+pub struct TestManagementTests {
+    #[cfg_attr(feature = "serde", serde(serialize_with = "sorted::test_management_tests"))]
+    modules: HashMap<String, HashMap<String, HashMap<String, TestManagementTest>>>,
+}
+
+impl TestManagementTests {
+    #[allow(dead_code)]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Inserts the test properties, keeping the existing entry if the test is already present
+    #[allow(dead_code)]
+    pub fn insert(&mut self, test: TestManagementTest) -> bool {
+        let tests = self.modules
+            .entry(test.module_name.clone())
+            .or_default()
+            .entry(test.suite_name.clone())
+            .or_default();
+        if tests.contains_key(&test.test_name) {
+            return false;
+        }
+        tests.insert(test.test_name.clone(), test);
+        true
+    }
+
+    #[allow(dead_code)]
+    pub fn get(&self, module_name: impl AsRef<str>, suite_name: impl AsRef<str>, test_name: impl AsRef<str>) -> Option<&TestManagementTest> {
+        self.modules
+            .get(module_name.as_ref())
+            .and_then(|suites| suites.get(suite_name.as_ref()))
+            .and_then(|tests| tests.get(test_name.as_ref()))
+    }
+
+    #[allow(dead_code)]
+    pub fn contains(&self, module_name: impl AsRef<str>, suite_name: impl AsRef<str>, test_name: impl AsRef<str>) -> bool {
+        self.get(module_name, suite_name, test_name).is_some()
+    }
+
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.modules.is_empty()
+    }
+
+    #[allow(dead_code)]
+    pub fn len(&self) -> usize {
+        self.modules.values().flat_map(|suites| suites.values()).map(HashMap::len).sum()
+    }
+
+    #[allow(dead_code)]
+    pub fn modules(&self) -> impl Iterator<Item = &str> {
+        self.modules.keys().map(String::as_str)
+    }
+
+    #[allow(dead_code)]
+    pub fn suites(&self, module_name: impl AsRef<str>) -> impl Iterator<Item = &str> {
+        self.modules.get(module_name.as_ref()).into_iter().flat_map(|suites| suites.keys().map(String::as_str))
+    }
+
+    #[allow(dead_code)]
+    pub fn iter(&self) -> impl Iterator<Item = &TestManagementTest> {
+        self.modules.values().flat_map(|suites| suites.values()).flat_map(|tests| tests.values())
+    }
+
+    #[allow(dead_code)]
+    pub fn quarantined(&self) -> impl Iterator<Item = &TestManagementTest> {
+        self.iter().filter(|test| test.quarantined)
+    }
+
+    #[allow(dead_code)]
+    pub fn disabled(&self) -> impl Iterator<Item = &TestManagementTest> {
+        self.iter().filter(|test| test.disabled)
+    }
+
+    #[allow(dead_code)]
+    pub fn attempt_to_fix(&self) -> impl Iterator<Item = &TestManagementTest> {
+        self.iter().filter(|test| test.attempt_to_fix)
+    }
+
+    #[allow(dead_code)]
+    pub fn filter_module(&self, module_name: impl AsRef<str>) -> TestManagementTests {
+        self.iter().filter(|test| test.module_name == module_name.as_ref()).cloned().collect()
+    }
+
+    #[allow(dead_code)]
+    pub fn filter_suite(&self, module_name: impl AsRef<str>, suite_name: impl AsRef<str>) -> TestManagementTests {
+        self.iter()
+            .filter(|test| test.module_name == module_name.as_ref() && test.suite_name == suite_name.as_ref())
+            .cloned()
+            .collect()
+    }
+}
+
+impl FromIterator<TestManagementTest> for TestManagementTests {
+    fn from_iter<I: IntoIterator<Item = TestManagementTest>>(iter: I) -> Self {
+        let mut test_management_tests = TestManagementTests::new();
+        for test in iter {
+            test_management_tests.insert(test);
+        }
+        test_management_tests
+    }
+}
+
+impl Display for TestManagementTests {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let mut tests: Vec<_> = self.iter().collect();
+        tests.sort_unstable_by(|a, b| {
+            (&a.module_name, &a.suite_name, &a.test_name).cmp(&(&b.module_name, &b.suite_name, &b.test_name))
+        });
+        for test in tests {
+            let flags: Vec<&str> = [
+                (test.quarantined, "quarantined"),
+                (test.disabled, "disabled"),
+                (test.attempt_to_fix, "attempt_to_fix"),
+            ]
+            .into_iter()
+            .filter_map(|(enabled, name)| enabled.then_some(name))
+            .collect();
+            writeln!(f, "{} > {} > {} [{}]", test.module_name, test.suite_name, test.test_name, flags.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/********************************
+    Test parameters
+*********************************/
+
+// Arguments of a parameterized test instance (rstest cases, proptest inputs, ...), serialized to the
+// canonical `test.parameters` JSON: `{"arguments":{"name":"value"},"metadata":{}}` with sorted keys
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TestParameters {
+    arguments: BTreeMap<String, String>,
+    metadata: BTreeMap<String, String>,
+}
+
+impl TestParameters {
+    #[allow(dead_code)]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Values are stored as strings, like their `Debug` or `Display` output
+    #[allow(dead_code)]
+    pub fn with_argument(mut self, name: impl Into<String>, value: impl ToString) -> Self {
+        self.arguments.insert(name.into(), value.to_string());
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn with_metadata(mut self, key: impl Into<String>, value: impl ToString) -> Self {
+        self.metadata.insert(key.into(), value.to_string());
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn arguments(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.arguments.iter().map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.arguments.is_empty() && self.metadata.is_empty()
+    }
+
+    // Parses a `test.parameters` JSON, like the ones listed by the backend. An empty string or object means no
+    // parameters. Other values than strings are kept as JSON, numbers in their shortest form (`1.0` is `1`).
+    #[cfg(feature = "serde")]
+    #[allow(dead_code)]
+    pub fn from_json(json: impl AsRef<str>) -> Option<Self> {
+        use serde_json::{Map, Value};
+
+        fn to_map(value: Option<&Value>) -> Option<BTreeMap<String, String>> {
+            let entries = match value {
+                None => return Some(BTreeMap::new()),
+                Some(Value::Object(entries)) => entries,
+                Some(_) => return None,
+            };
+            Some(entries.iter().map(|(key, value)| (key.clone(), to_text(value))).collect())
+        }
+
+        fn to_text(value: &Value) -> String {
+            match value {
+                Value::String(text) => text.clone(),
+                Value::Number(number) => {
+                    if let Some(integer) = number.as_i64() {
+                        integer.to_string()
+                    } else if let Some(integer) = number.as_u64() {
+                        integer.to_string()
+                    } else {
+                        number.as_f64().map_or_else(|| number.to_string(), |float| float.to_string())
+                    }
+                }
+                value => value.to_string(),
+            }
+        }
+
+        let json = json.as_ref();
+        if json.trim().is_empty() {
+            return Some(Self::new());
+        }
+        let parameters: Map<String, Value> = serde_json::from_str(json).ok()?;
+        if parameters.keys().any(|key| key != "arguments" && key != "metadata") {
+            return None;
+        }
+        Some(Self {
+            arguments: to_map(parameters.get("arguments"))?,
+            metadata: to_map(parameters.get("metadata"))?,
+        })
+    }
+
+    #[allow(dead_code)]
+    pub fn to_json(&self) -> String {
+        fn json_object(map: &BTreeMap<String, String>) -> String {
+            let entries: Vec<String> = map
+                .iter()
+                .map(|(key, value)| format!("{}:{}", tags::json_string(key), tags::json_string(value)))
+                .collect();
+            format!("{{{}}}", entries.join(","))
+        }
+        format!("{{\"arguments\":{},\"metadata\":{}}}", json_object(&self.arguments), json_object(&self.metadata))
+    }
+}
+
+// Helper: compares two `test.parameters` values, as JSON when possible and otherwise as text
+fn same_parameters(left: &str, right: &str) -> bool {
+    #[cfg(feature = "serde")]
+    if let (Some(left), Some(right)) = (TestParameters::from_json(left), TestParameters::from_json(right)) {
+        return left == right;
+    }
+    let is_empty = |parameters: &str| matches!(parameters.trim(), "" | "{}" | r#"{"arguments":{},"metadata":{}}"#);
+    left == right || (is_empty(left) && is_empty(right))
+}
+
+impl<N: Into<String>, V: ToString> FromIterator<(N, V)> for TestParameters {
+    fn from_iter<I: IntoIterator<Item = (N, V)>>(iter: I) -> Self {
+        iter.into_iter().fold(TestParameters::new(), |parameters, (name, value)| parameters.with_argument(name, value))
+    }
+}
+
+impl Display for TestParameters {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_json())
+    }
+}
+
+// Known tests used to tag new tests, loaded on the first test created in the session.
+// `Some(None)` means new test detection is disabled or the list is not available.
+static NEW_TEST_DETECTION: Mutex<Option<Option<Arc<KnownTests>>>> = Mutex::new(None);
+
+fn new_test_detection_known_tests() -> Option<Arc<KnownTests>> {
+    let mut state = NEW_TEST_DETECTION.lock().unwrap_or_else(|e| e.into_inner());
+    state
+        .get_or_insert_with(|| {
+            let settings = TestSession::load_settings();
+            if !settings.known_tests_enabled {
+                return None;
+            }
+            // An empty list means the backend could not provide it, so we can't tell which tests are new.
+            let known_tests = TestSession::load_known_tests();
+            if known_tests.is_empty() {
+                None
+            } else {
+                Some(Arc::new(known_tests))
+            }
+        })
+        .clone()
+}
+
+// Skippable tests, loaded on the first `Test::is_skippable` call of the session.
+// `Some(None)` means test skipping is disabled.
+static SKIPPABLE_TESTS: Mutex<Option<Option<Arc<SkippableTests>>>> = Mutex::new(None);
+
+fn skippable_tests() -> Option<Arc<SkippableTests>> {
+    let mut state = SKIPPABLE_TESTS.lock().unwrap_or_else(|e| e.into_inner());
+    state
+        .get_or_insert_with(|| {
+            let settings = TestSession::load_settings();
+            settings.tests_skipping.then(|| Arc::new(TestSession::load_skippable_tests()))
+        })
+        .clone()
+}
+
+// Forgets the test lists loaded in the session, for the next session or fixture
+fn reset_test_lists() {
+    *NEW_TEST_DETECTION.lock().unwrap_or_else(|e| e.into_inner()) = None;
+    *SKIPPABLE_TESTS.lock().unwrap_or_else(|e| e.into_inner()) = None;
+}
+
+// Normalizes the paths reported in the current session, see `TestSession::set_path_normalizer`
+static PATH_NORMALIZER: Mutex<Option<Arc<PathNormalizer>>> = Mutex::new(None);
+
+fn path_normalizer() -> Option<Arc<PathNormalizer>> {
+    PATH_NORMALIZER.lock().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+fn set_path_normalizer(normalizer: Option<PathNormalizer>) {
+    *PATH_NORMALIZER.lock().unwrap_or_else(|e| e.into_inner()) = normalizer.map(Arc::new);
+}
+
+// Helper: makes the paths inside the repository found in a stack trace relative to its root
+fn normalize_stacktrace(stacktrace: &str) -> Cow<'_, str> {
+    match path_normalizer() {
+        Some(normalizer) => Cow::Owned(normalizer.normalize_stacktrace(stacktrace).into_owned()),
+        None => Cow::Borrowed(stacktrace),
+    }
+}
+
+// Helper: returns the path to report for a source file and whether it must be tagged as outside the repository,
+// or `None` if it must be rejected
+fn normalize_source_file(file: &str) -> Option<(String, bool)> {
+    let Some(normalizer) = path_normalizer() else {
+        return Some((file.to_string(), false));
+    };
+    match normalizer.normalize(file) {
+        NormalizedPath::Repository(path) => Some((path, false)),
+        NormalizedPath::Outside(path) => match normalizer.policy() {
+            OutsideRepositoryPolicy::Keep => Some((file.to_string(), false)),
+            OutsideRepositoryPolicy::Tag => Some((path, true)),
+            OutsideRepositoryPolicy::Reject => None,
+        },
+    }
+}
+
+// What to do with the spans still open when the session is closed, see `TestSession::set_leak_policy`
+static LEAK_POLICY: Mutex<LeakPolicy> = Mutex::new(LeakPolicy::Ignore);
+
+// Batcher collecting the tests coverage, see `TestSession::set_coverage_batcher`
+static COVERAGE_BATCHER: Mutex<Option<Arc<CoverageBatcher>>> = Mutex::new(None);
+
+fn coverage_batcher() -> Option<Arc<CoverageBatcher>> {
+    COVERAGE_BATCHER.lock().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+// Fixture replacing the backend settings and test lists, see `TestSession::set_fixture`
+static FIXTURE: Mutex<Option<Arc<Fixture>>> = Mutex::new(None);
+
+fn set_fixture(fixture: Option<Fixture>) {
+    *FIXTURE.lock().unwrap_or_else(|e| e.into_inner()) = fixture.map(Arc::new);
+    reset_test_lists();
+}
+
+// Error of the fixture file set in DD_TEST_OPTIMIZATION_FIXTURE, see `TestSession::fixture_error`
+#[cfg(feature = "fixture")]
+static FIXTURE_ERROR: Mutex<Option<Arc<FixtureError>>> = Mutex::new(None);
+
+fn from_fixture<T>(get: impl FnOnce(&Fixture) -> T) -> Option<T> {
+    let fixture = FIXTURE.lock().unwrap_or_else(|e| e.into_inner()).clone();
+    fixture.map(|fixture| get(&fixture))
+}
+
+// On-disk cache for the settings and test lists, enabled with DD_TEST_OPTIMIZATION_CACHE_DIR
+#[cfg(feature = "cache")]
+static CACHE: Mutex<Option<TestOptimizationCache>> = Mutex::new(None);
+
+#[cfg(feature = "cache")]
+fn set_cache(cache: Option<TestOptimizationCache>) {
+    *CACHE.lock().unwrap_or_else(|e| e.into_inner()) = cache;
+}
+
+// Helper: returns the cached value if available, otherwise loads it and stores it when `should_store` allows it
+#[cfg(feature = "cache")]
+fn cached<T: Serialize + DeserializeOwned>(name: &str, should_store: impl Fn(&T) -> bool, load: impl FnOnce() -> T) -> T {
+    let cache = CACHE.lock().unwrap_or_else(|e| e.into_inner()).clone();
+    let Some(cache) = cache else {
+        return load();
+    };
+    if let Some(value) = cache.get(name) {
+        return value;
+    }
+    let value = load();
+    if should_store(&value) {
+        // A failure to write the cache only means the next process will query the backend again
+        _ = cache.set(name, &value);
+    }
+    value
+}
+
+#[cfg(not(feature = "cache"))]
+fn cached<T>(_name: &str, _should_store: impl Fn(&T) -> bool, load: impl FnOnce() -> T) -> T {
+    load()
+}
+
+// Helper: reports a warning through the `tracing` or `log` facade, returns false if neither feature is enabled
+#[cfg(feature = "tracing")]
+fn warn(message: fmt::Arguments) -> bool {
+    tracing::warn!("{}", message);
+    true
+}
+
+#[cfg(all(feature = "log", not(feature = "tracing")))]
+fn warn(message: fmt::Arguments) -> bool {
+    log::warn!("{}", message);
+    true
+}
+
+#[cfg(not(any(feature = "log", feature = "tracing")))]
+fn warn(_message: fmt::Arguments) -> bool {
+    false
+}
+
+// Session shared by the `TestSession` handles of the process, like the ones of tests running in parallel.
+// The first handle initializes the library and the last one closed shuts it down, the settings changed with
+// a handle (fixture, leak policy, path normalizer...) apply to all of them.
+struct ActiveSession {
+    session_id: u64,
+    use_mock_tracer: bool,
+    handles: usize,
+    exit_code: i32,
+}
+
+static ACTIVE_SESSION: Mutex<Option<ActiveSession>> = Mutex::new(None);
 
 /********************************
     Test session
@@ -167,6 +920,15 @@ impl TestSession {
         Self::init_with_values(LANGUAGE_NAME, RUNTIME_NAME, Self::runtime_version(), Some(working_dir), true)
     }
 
+    // Offline session: the fixture replaces the backend settings and test lists, and the spans are recorded by
+    // the mock tracer, so nothing reaches the backend
+    #[allow(dead_code)]
+    pub fn init_with_fixture(fixture: Fixture) -> Self {
+        let session = Self::init_mock();
+        session.set_fixture(Some(fixture));
+        session
+    }
+
     #[allow(dead_code)]
     pub fn init_with_values(
         language_name: impl AsRef<str>,
@@ -184,12 +946,22 @@ impl TestSession {
             _rt0_amd64_windows_lib()
         }
 
+        let mut active_session = ACTIVE_SESSION.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(active_session) = active_session.as_mut() {
+            // Already initialized by another handle, keep its session instead of resetting it
+            active_session.handles += 1;
+            record_mock_trace(active_session.session_id);
+            return Self {
+                session_id: active_session.session_id,
+            };
+        }
+
         // Create CStrings for the required parameters
         let language_name_cstring = CString::new(language_name.as_ref()).unwrap();
         let runtime_name_cstring = CString::new(runtime_name.as_ref()).unwrap();
         let runtime_version_cstring = CString::new(runtime_version.as_ref()).unwrap();
         // Create an optional CString for working_directory if provided
-        let working_directory_cstring = working_directory.map(|wd| CString::new(wd.as_ref()).unwrap());
+        let working_directory_cstring = working_directory.as_ref().map(|wd| CString::new(wd.as_ref()).unwrap());
 
         // Build the initialization options struct, using as_ptr() so the memory is managed automatically
         let init_options = topt_InitOptions {
@@ -210,10 +982,46 @@ impl TestSession {
         };
 
         // Initialize the library with the provided options
+        *LEAK_POLICY.lock().unwrap_or_else(|e| e.into_inner()) = LeakPolicy::default();
+        set_path_normalizer(
+            working_directory
+                .as_ref()
+                .map(|wd| PathBuf::from(wd.as_ref()))
+                .or_else(|| std::env::current_dir().ok())
+                .map(PathNormalizer::discover),
+        );
+        #[cfg(feature = "fixture")]
+        // An invalid fixture file must not break the test run, the backend data is used instead
+        set_fixture(match Fixture::from_env().transpose() {
+            Ok(fixture) => {
+                *FIXTURE_ERROR.lock().unwrap_or_else(|e| e.into_inner()) = None;
+                fixture
+            }
+            Err(e) => {
+                warn(format_args!("{}: {}, ignoring it", FIXTURE_ENV, e));
+                *FIXTURE_ERROR.lock().unwrap_or_else(|e| e.into_inner()) = Some(Arc::new(e));
+                None
+            }
+        });
+        #[cfg(not(feature = "fixture"))]
+        set_fixture(None);
+        #[cfg(feature = "cache")]
+        set_cache(if use_mock_tracer {
+            None
+        } else {
+            TestOptimizationCache::from_env(working_directory.as_ref().map(|wd| wd.as_ref()))
+        });
         let initialized = unsafe { Bool_to_bool(topt_initialize(init_options)) };
         if initialized {
             let mut now = get_now();
             let session_result = unsafe { topt_session_create(null_mut(), null_mut(), &mut now) };
+            record_mock_trace(session_result.session_id);
+            *active_session = Some(ActiveSession {
+                session_id: session_result.session_id,
+                use_mock_tracer,
+                handles: 1,
+                exit_code: 0,
+            });
             Self {
                 session_id: session_result.session_id,
             }
@@ -252,7 +1060,7 @@ impl TestSession {
     ) -> bool {
         let error_type_cstring = CString::new(error_type.as_ref()).unwrap();
         let error_message_cstring = CString::new(error_message.as_ref()).unwrap();
-        let error_stacktrace_cstring = CString::new(error_stacktrace.as_ref()).unwrap();
+        let error_stacktrace_cstring = CString::new(normalize_stacktrace(error_stacktrace.as_ref()).as_ref()).unwrap();
 
         unsafe {
             Bool_to_bool(topt_session_set_error(
@@ -264,17 +1072,79 @@ impl TestSession {
         }
     }
 
+    // Reports the total line coverage of the run, must be called before `close`. NaN and infinite values are rejected.
+    #[allow(dead_code)]
+    pub fn report_total_coverage(&self, percentage: f64) -> bool {
+        if !percentage.is_finite() {
+            return false;
+        }
+        self.set_number_tag(tags::TEST_CODE_COVERAGE_LINES_PCT, percentage.clamp(0.0, 100.0))
+    }
+
+    // Reports the total line coverage computed from an lcov file, like the one produced by `cargo llvm-cov --lcov`
+    #[allow(dead_code)]
+    pub fn import_total_coverage_lcov(&self, path: impl AsRef<Path>) -> Result<f64, CoverageError> {
+        let percentage = CoverageSummary::from_lcov_file(path)?.percentage();
+        self.report_total_coverage(percentage);
+        Ok(percentage)
+    }
+
+    // Each handle returned by `init*` must be closed once, the session is closed with the first non-zero exit code
+    // when the last handle is closed
     #[allow(dead_code)]
     pub fn close(&self, exit_code: i32) {
+        let exit_code = if panicking() { 1 } else { exit_code };
+        let mut active_session = ACTIVE_SESSION.lock().unwrap_or_else(|e| e.into_inner());
+        let (exit_code, use_mock_tracer) = match active_session.take() {
+            Some(mut active) if active.session_id == self.session_id => {
+                if active.exit_code == 0 {
+                    active.exit_code = exit_code;
+                }
+                active.handles -= 1;
+                if active.handles > 0 {
+                    *active_session = Some(active);
+                    return;
+                }
+                (active.exit_code, active.use_mock_tracer)
+            }
+            active => {
+                *active_session = active;
+                (exit_code, false)
+            }
+        };
+        // Send the pending coverage before the session is closed
+        if let Some(batcher) = COVERAGE_BATCHER.lock().unwrap_or_else(|e| e.into_inner()).take() {
+            batcher.close();
+        }
+        let mut exit_code = exit_code;
+        // Only the mock tracer knows the open spans
+        let leak_policy = self.get_leak_policy();
+        if use_mock_tracer && leak_policy != LeakPolicy::Ignore {
+            let report = MockTracer::leak_report();
+            if !report.is_empty() {
+                // stderr is the fallback when neither the `tracing` nor the `log` feature is enabled
+                if !warn(format_args!("{}", report)) {
+                    eprint!("{}", report);
+                }
+                if leak_policy == LeakPolicy::Fail && exit_code == 0 {
+                    exit_code = 1;
+                }
+            }
+        }
         let mut now = get_now();
         unsafe {
-            if panicking() {
-                topt_session_close(self.session_id, 1,  &mut now);
-            } else {
-                topt_session_close(self.session_id, exit_code,  &mut now);
-            }
+            topt_session_close(self.session_id, exit_code,  &mut now);
             topt_shutdown();
         }
+        reset_test_lists();
+        #[cfg(feature = "log")]
+        clear_test_logs();
+    }
+
+    // Module for the doc tests of a crate, named by `strategy` and labeled with the rustdoc framework
+    #[allow(dead_code)]
+    pub fn create_doc_test_module(&self, strategy: &NamingStrategy) -> TestModule {
+        self.create_module(strategy.module_name(&TestTarget::Doc), DOC_TEST_FRAMEWORK, Self::runtime_version())
     }
 
     #[allow(dead_code)]
@@ -299,15 +1169,99 @@ impl TestSession {
             )
         };
 
+        record_mock_trace(module_result.module_id);
         TestModule {
             session_id: self.session_id,
             module_id: module_result.module_id,
+            module_name: name.as_ref().to_string(),
+        }
+    }
+
+    // Replaces the on-disk cache used by the settings and test list getters, `None` disables it
+    #[cfg(feature = "cache")]
+    #[allow(dead_code)]
+    pub fn set_cache(&self, cache: Option<TestOptimizationCache>) {
+        set_cache(cache);
+    }
+
+    #[cfg(feature = "cache")]
+    #[allow(dead_code)]
+    pub fn get_cache(&self) -> Option<TestOptimizationCache> {
+        CACHE.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    // Replaces the normalizer used for coverage, sources and stack traces, `None` reports the paths as is.
+    // By default the repository root is discovered from the session working directory.
+    #[allow(dead_code)]
+    pub fn set_path_normalizer(&self, normalizer: Option<PathNormalizer>) {
+        set_path_normalizer(normalizer);
+    }
+
+    #[allow(dead_code)]
+    pub fn get_path_normalizer(&self) -> Option<Arc<PathNormalizer>> {
+        path_normalizer()
+    }
+
+    // Checks the mock tracer for spans still open when the session is closed, sessions without it are not checked
+    #[allow(dead_code)]
+    pub fn set_leak_policy(&self, policy: LeakPolicy) {
+        *LEAK_POLICY.lock().unwrap_or_else(|e| e.into_inner()) = policy;
+    }
+
+    #[allow(dead_code)]
+    pub fn get_leak_policy(&self) -> LeakPolicy {
+        *LEAK_POLICY.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // Collects the coverage of the tests and sends it in batches instead of once per test,
+    // `None` sends the pending coverage and goes back to sending it per test
+    #[allow(dead_code)]
+    pub fn set_coverage_batcher(&self, batcher: Option<CoverageBatcher>) {
+        let previous = std::mem::replace(
+            &mut *COVERAGE_BATCHER.lock().unwrap_or_else(|e| e.into_inner()),
+            batcher.map(Arc::new),
+        );
+        if let Some(previous) = previous {
+            previous.flush();
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn get_coverage_batcher(&self) -> Option<Arc<CoverageBatcher>> {
+        coverage_batcher()
+    }
+
+    #[allow(dead_code)]
+    pub fn flush_coverage(&self) {
+        if let Some(batcher) = coverage_batcher() {
+            batcher.flush();
         }
     }
 
+    // Replaces the settings and test lists returned by the getters, `None` goes back to the backend ones
+    #[allow(dead_code)]
+    pub fn set_fixture(&self, fixture: Option<Fixture>) {
+        set_fixture(fixture);
+    }
+
+    // Error of the fixture file set in DD_TEST_OPTIMIZATION_FIXTURE, ignored when the session was initialized
+    #[cfg(feature = "fixture")]
+    #[allow(dead_code)]
+    pub fn fixture_error(&self) -> Option<Arc<FixtureError>> {
+        FIXTURE_ERROR.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
     #[allow(dead_code)]
     pub fn get_settings(&self) -> Settings {
-        unsafe {
+        Self::load_settings()
+    }
+
+    fn load_settings() -> Settings {
+        if let Some(settings) = from_fixture(|fixture| fixture.settings.clone()) {
+            return settings;
+        }
+        // A failed backend call returns the default settings, which must not disable every feature for the cache TTL
+        cached("settings", |settings: &Settings| *settings != Settings::default(), || unsafe {
             let settings_response = topt_get_settings();
             Settings {
                 code_coverage: Bool_to_bool(settings_response.code_coverage),
@@ -331,24 +1285,42 @@ impl TestSession {
                     attempt_to_fix_retries: settings_response.test_management.attempt_to_fix_retries,
                 }
             }
-        }
+        })
     }
 
     #[allow(dead_code)]
     pub fn get_flaky_test_retries_settings(&self) -> FlakyTestRetriesSettings {
-        unsafe {
-            let response = topt_get_flaky_test_retries_settings();
-            FlakyTestRetriesSettings {
-                retry_count: response.retry_count,
-                total_retry_count: response.total_retry_count,
-            }
+        Self::load_flaky_test_retries_settings()
+    }
+
+    fn load_flaky_test_retries_settings() -> FlakyTestRetriesSettings {
+        if let Some(flaky_test_retries_settings) = from_fixture(|fixture| fixture.flaky_test_retries_settings.clone()) {
+            return flaky_test_retries_settings;
         }
+        cached(
+            "flaky_test_retries_settings",
+            |settings: &FlakyTestRetriesSettings| *settings != FlakyTestRetriesSettings::default(),
+            || unsafe {
+                let response = topt_get_flaky_test_retries_settings();
+                FlakyTestRetriesSettings {
+                    retry_count: response.retry_count,
+                    total_retry_count: response.total_retry_count,
+                }
+            },
+        )
     }
 
     #[allow(dead_code)]
-    pub fn get_known_tests(&self) -> HashMap<String, HashMap<String, Vec<String>>> {
-        unsafe {
-            let mut modules_map: HashMap<String, HashMap<String, Vec<String>>> = HashMap::new();
+    pub fn get_known_tests(&self) -> KnownTests {
+        Self::load_known_tests()
+    }
+
+    fn load_known_tests() -> KnownTests {
+        if let Some(known_tests) = from_fixture(|fixture| fixture.known_tests.clone()) {
+            return known_tests;
+        }
+        cached("known_tests", |known_tests: &KnownTests| !known_tests.is_empty(), || unsafe {
+            let mut known_tests_index = KnownTests::new();
             let known_tests = topt_get_known_tests();
             for i in 0..known_tests.len {
                 let element = &*known_tests.data.add(i);
@@ -361,19 +1333,24 @@ impl TestSession {
                 let suite_name_string = suite_name_c.to_string_lossy().into_owned();
                 let test_name = test_name_c.to_string_lossy().into_owned();
 
-                let suites_map = modules_map.entry(module_name_string).or_insert_with(HashMap::new);
-                let tests_vec = suites_map.entry(suite_name_string).or_insert_with(Vec::new);
-                tests_vec.push(test_name);
+                known_tests_index.insert(module_name_string, suite_name_string, test_name);
             }
             topt_free_known_tests(known_tests);
-            modules_map
-        }
+            known_tests_index
+        })
     }
 
     #[allow(dead_code)]
-    pub fn get_skippable_tests(&self) -> HashMap<String, HashMap<String, Vec<SkippableTest>>> {
-        unsafe {
-            let mut suites_map: HashMap<String, HashMap<String, Vec<SkippableTest>>> = HashMap::new();
+    pub fn get_skippable_tests(&self) -> SkippableTests {
+        Self::load_skippable_tests()
+    }
+
+    fn load_skippable_tests() -> SkippableTests {
+        if let Some(skippable_tests) = from_fixture(|fixture| fixture.skippable_tests.clone()) {
+            return skippable_tests;
+        }
+        cached("skippable_tests", |skippable_tests: &SkippableTests| !skippable_tests.is_empty(), || unsafe {
+            let mut skippable_tests_index = SkippableTests::new();
             let skippable_tests = topt_get_skippable_tests();
             for i in 0..skippable_tests.len {
                 let element = &*skippable_tests.data.add(i);
@@ -383,30 +1360,29 @@ impl TestSession {
                 let parameters_c = CStr::from_ptr(element.parameters);
                 let custom_configurations_json_c = CStr::from_ptr(element.custom_configurations_json);
 
-                let suite_name_string = suite_name_c.to_string_lossy().into_owned();
-                let test_name_string = test_name_c.to_string_lossy().into_owned();
-                let parameters_string = parameters_c.to_string_lossy().into_owned();
-                let custom_configurations_json_string = custom_configurations_json_c.to_string_lossy().into_owned();
-
-                let suites_map_entry = suites_map.entry(suite_name_string.clone()).or_insert_with(HashMap::new);
-                let tests_vec = suites_map_entry.entry(test_name_string.clone()).or_insert_with(Vec::new);
-
-                tests_vec.push(SkippableTest {
-                    suite_name: suite_name_string,
-                    test_name: test_name_string,
-                    parameters: parameters_string,
-                    custom_configurations_json: custom_configurations_json_string,
+                skippable_tests_index.insert(SkippableTest {
+                    suite_name: suite_name_c.to_string_lossy().into_owned(),
+                    test_name: test_name_c.to_string_lossy().into_owned(),
+                    parameters: parameters_c.to_string_lossy().into_owned(),
+                    custom_configurations_json: custom_configurations_json_c.to_string_lossy().into_owned(),
                 });
             }
             topt_free_skippable_tests(skippable_tests);
-            suites_map
-        }
+            skippable_tests_index
+        })
     }
 
     #[allow(dead_code)]
-    pub fn get_test_management_tests(&self) -> HashMap<String, HashMap<String, HashMap<String, TestManagementTest>>> {
-        unsafe {
-            let mut modules_map: HashMap<String, HashMap<String, HashMap<String, TestManagementTest>>> =  HashMap::new();
+    pub fn get_test_management_tests(&self) -> TestManagementTests {
+        Self::load_test_management_tests()
+    }
+
+    fn load_test_management_tests() -> TestManagementTests {
+        if let Some(test_management_tests) = from_fixture(|fixture| fixture.test_management_tests.clone()) {
+            return test_management_tests;
+        }
+        cached("test_management_tests", |tests: &TestManagementTests| !tests.is_empty(), || unsafe {
+            let mut test_management_tests_index = TestManagementTests::new();
             let test_management_tests = topt_get_test_management_tests();
             for i in 0..test_management_tests.len {
                 let element = &*test_management_tests.data.add(i);
@@ -415,24 +1391,18 @@ impl TestSession {
                 let suite_name_c = CStr::from_ptr(element.suite_name);
                 let test_name_c = CStr::from_ptr(element.test_name);
 
-                let module_name_string = module_name_c.to_string_lossy().into_owned();
-                let suite_name_string = suite_name_c.to_string_lossy().into_owned();
-                let test_name_string = test_name_c.to_string_lossy().into_owned();
-
-                let modules_map_entry = modules_map.entry(module_name_string.clone()).or_insert_with(HashMap::new);
-                let suites_map_entry = modules_map_entry.entry(suite_name_string.clone()).or_insert_with(HashMap::new);
-                _ = suites_map_entry.entry(test_name_string.clone()).or_insert(TestManagementTest {
-                    module_name: module_name_string,
-                    suite_name: suite_name_string,
-                    test_name: test_name_string,
+                test_management_tests_index.insert(TestManagementTest {
+                    module_name: module_name_c.to_string_lossy().into_owned(),
+                    suite_name: suite_name_c.to_string_lossy().into_owned(),
+                    test_name: test_name_c.to_string_lossy().into_owned(),
                     quarantined: Bool_to_bool(element.quarantined),
                     disabled: Bool_to_bool(element.disabled),
                     attempt_to_fix: Bool_to_bool(element.attempt_to_fix),
                 });
             }
             topt_free_test_management_tests(test_management_tests);
-            modules_map
-        }
+            test_management_tests_index
+        })
     }
 }
 
@@ -444,6 +1414,7 @@ impl TestSession {
 pub struct TestModule {
     session_id: u64,
     pub module_id: u64,
+    module_name: String,
 }
 impl TestModule {
     #[allow(dead_code)]
@@ -480,7 +1451,7 @@ impl TestModule {
     ) -> bool {
         let error_type_cstring = CString::new(error_type.as_ref()).unwrap();
         let error_message_cstring = CString::new(error_message.as_ref()).unwrap();
-        let error_stacktrace_cstring = CString::new(error_stacktrace.as_ref()).unwrap();
+        let error_stacktrace_cstring = CString::new(normalize_stacktrace(error_stacktrace.as_ref()).as_ref()).unwrap();
 
         unsafe {
             Bool_to_bool(topt_module_set_error(
@@ -511,10 +1482,13 @@ impl TestModule {
                 &mut now,
             )
         };
+        record_mock_trace(suite_result.suite_id);
         TestSuite {
             suite_id: suite_result.suite_id,
             module_id: self.module_id,
             session_id: self.session_id,
+            module_name: self.module_name.clone(),
+            suite_name: name.as_ref().to_string(),
         }
     }
 }
@@ -528,11 +1502,13 @@ pub struct TestSuite {
     pub suite_id: u64,
     module_id: u64,
     session_id: u64,
+    module_name: String,
+    suite_name: String,
 }
 impl TestSuite {
     #[allow(dead_code)]
     pub fn get_module(&self) -> TestModule {
-        TestModule { module_id: self.module_id, session_id: self.session_id }
+        TestModule { module_id: self.module_id, session_id: self.session_id, module_name: self.module_name.clone() }
     }
 
     #[allow(dead_code)]
@@ -569,7 +1545,7 @@ impl TestSuite {
     ) -> bool {
         let error_type_cstring = CString::new(error_type.as_ref()).unwrap();
         let error_message_cstring = CString::new(error_message.as_ref()).unwrap();
-        let error_stacktrace_cstring = CString::new(error_stacktrace.as_ref()).unwrap();
+        let error_stacktrace_cstring = CString::new(normalize_stacktrace(error_stacktrace.as_ref()).as_ref()).unwrap();
         unsafe {
             Bool_to_bool(topt_suite_set_error(
                 self.suite_id,
@@ -587,7 +1563,13 @@ impl TestSuite {
         start_line: *const i32,
         end_line: *const i32,
     ) -> bool {
-        let file_cstring = CString::new(file.as_ref()).unwrap();
+        let Some((file, outside_repository)) = normalize_source_file(file.as_ref()) else {
+            return false;
+        };
+        if outside_repository {
+            self.set_string_tag(tags::TEST_SOURCE_OUTSIDE_REPOSITORY, "true");
+        }
+        let file_cstring = CString::new(file).unwrap();
         unsafe {
             Bool_to_bool(topt_suite_set_source(
                 self.suite_id,
@@ -608,7 +1590,29 @@ impl TestSuite {
 
     #[allow(dead_code)]
     pub fn create_test(&self, name: impl AsRef<str>) -> Test {
-        let test_name_cstring = CString::new(name.as_ref()).unwrap();
+        self.start_test(name.as_ref(), None)
+    }
+
+    // Creates one instance of a parameterized test, instances share the name and differ by `test.parameters`
+    #[allow(dead_code)]
+    pub fn create_parameterized_test(&self, name: impl AsRef<str>, parameters: &TestParameters) -> Test {
+        self.start_test(name.as_ref(), Some(parameters.to_json()))
+    }
+
+    // Creates a doc test named by `DocTestName::names`, with the code block as source.
+    // The suite is expected to be the one named by the same call.
+    #[allow(dead_code)]
+    pub fn create_doc_test(&self, strategy: &NamingStrategy, doc_test: &DocTestName) -> Test {
+        let test = self.create_test(doc_test.names(strategy).test);
+        if !test.set_test_source(&doc_test.file, &doc_test.line, null()) {
+            // The file can't be reported (like a path rejected by the outside repository policy), keep the line
+            test.set_number_tag(tags::TEST_SOURCE_START, doc_test.line.into());
+        }
+        test
+    }
+
+    fn start_test(&self, name: &str, parameters: Option<String>) -> Test {
+        let test_name_cstring = CString::new(name).unwrap();
         let mut now = get_now();
         let test_result = unsafe {
             topt_test_create(
@@ -617,12 +1621,29 @@ impl TestSuite {
                 &mut now,
             )
         };
-        Test {
+        record_mock_trace(test_result.test_id);
+        #[cfg(feature = "log")]
+        start_test_logs(test_result.test_id);
+        let test = Test {
             test_id: test_result.test_id,
             suite_id: self.suite_id,
             module_id: self.module_id,
             session_id: self.session_id,
+            module_name: self.module_name.clone(),
+            suite_name: self.suite_name.clone(),
+            test_name: name.to_string(),
+            // Known tests only carry names, so every instance of a known parameterized test is known
+            is_new: new_test_detection_known_tests()
+                .is_some_and(|known_tests| !known_tests.contains(&self.module_name, &self.suite_name, name)),
+            parameters,
+        };
+        if let Some(parameters) = &test.parameters {
+            test.set_string_tag(tags::TEST_PARAMETERS, parameters);
         }
+        if test.is_new {
+            test.set_string_tag(tags::TEST_IS_NEW, "true");
+        }
+        test
     }
 }
 
@@ -630,7 +1651,7 @@ impl TestSuite {
     Test
 *********************************/
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum TestStatus {
     Pass = 0,
     Fail = 1,
@@ -643,11 +1664,66 @@ pub struct Test {
     suite_id: u64,
     module_id: u64,
     session_id: u64,
+    module_name: String,
+    suite_name: String,
+    test_name: String,
+    parameters: Option<String>,
+    is_new: bool,
 }
 impl Test {
     #[allow(dead_code)]
     pub fn get_suite(&self) -> TestSuite {
-        TestSuite { suite_id: self.suite_id, module_id: self.module_id,  session_id: self.session_id }
+        TestSuite {
+            suite_id: self.suite_id,
+            module_id: self.module_id,
+            session_id: self.session_id,
+            module_name: self.module_name.clone(),
+            suite_name: self.suite_name.clone(),
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn is_new(&self) -> bool {
+        self.is_new
+    }
+
+    // Canonical `test.parameters` JSON of a parameterized test instance
+    #[allow(dead_code)]
+    pub fn parameters(&self) -> Option<&str> {
+        self.parameters.as_deref()
+    }
+
+    // Whether test impact analysis lists this test instance (name and parameters) as skippable,
+    // always false when test skipping is disabled in the settings
+    #[allow(dead_code)]
+    pub fn is_skippable(&self) -> bool {
+        skippable_tests().is_some_and(|skippable_tests| skippable_tests.contains_test(self))
+    }
+
+    // Makes this test the current one in this thread, for the tracing layer, the test logger and `Span::start_in_current`
+    #[allow(dead_code)]
+    pub fn enter(&self) -> ContextGuard {
+        context::enter(Some(self.test_id), self.test_id, self.test_id)
+    }
+
+    // Context to propagate to child processes and HTTP calls made by this test
+    #[allow(dead_code)]
+    pub fn trace_context(&self) -> TraceContext {
+        TraceContext::local(self.test_id, self.test_id)
+    }
+
+    // Runs `f` inside a span under this test, see `in_span`
+    #[allow(dead_code)]
+    pub fn in_span<R>(&self, operation_name: impl AsRef<str>, f: impl FnOnce(&Span) -> R) -> R {
+        let _guard = self.enter();
+        run_in_span(self.test_id, self.test_id, operation_name.as_ref(), f)
+    }
+
+    // Makes this test the current one while the future runs
+    #[cfg(feature = "tokio")]
+    #[allow(dead_code)]
+    pub fn scope<F: Future>(&self, future: F) -> impl Future<Output = F::Output> {
+        context::scope(Some(self.test_id), self.test_id, self.test_id, future)
     }
 
     #[allow(dead_code)]
@@ -675,6 +1751,12 @@ impl Test {
         }
     }
 
+    // Marks the test as a retry of a previous execution
+    #[allow(dead_code)]
+    pub fn set_retry(&self, retry_reason: RetryReason) -> bool {
+        self.set_is_retry(true) && self.set_retry_reason(retry_reason)
+    }
+
     #[allow(dead_code)]
     pub fn set_error_info(
         &self,
@@ -684,7 +1766,7 @@ impl Test {
     ) -> bool {
         let error_type_cstring = CString::new(error_type.as_ref()).unwrap();
         let error_message_cstring = CString::new(error_message.as_ref()).unwrap();
-        let error_stacktrace_cstring = CString::new(error_stacktrace.as_ref()).unwrap();
+        let error_stacktrace_cstring = CString::new(normalize_stacktrace(error_stacktrace.as_ref()).as_ref()).unwrap();
         unsafe {
             Bool_to_bool(topt_test_set_error(
                 self.test_id,
@@ -702,7 +1784,13 @@ impl Test {
         start_line: *const i32,
         end_line: *const i32,
     ) -> bool {
-        let file_cstring = CString::new(file.as_ref()).unwrap();
+        let Some((file, outside_repository)) = normalize_source_file(file.as_ref()) else {
+            return false;
+        };
+        if outside_repository {
+            self.set_string_tag(tags::TEST_SOURCE_OUTSIDE_REPOSITORY, "true");
+        }
+        let file_cstring = CString::new(file).unwrap();
         unsafe {
             Bool_to_bool(topt_test_set_source(
                 self.test_id,
@@ -715,6 +1803,8 @@ impl Test {
 
     #[allow(dead_code)]
     pub fn close(&self, status: TestStatus) -> bool {
+        #[cfg(feature = "log")]
+        self.attach_logs(&status);
         let mut now = get_now();
         let close_options = topt_TestCloseOptions {
             status: status as u8,
@@ -735,6 +1825,8 @@ impl Test {
     pub fn close_with_skip_reason(&self, skip_reason: impl AsRef<str>) -> bool {
         let skip_reason_ref = skip_reason.as_ref();
         if !skip_reason_ref.is_empty() {
+            #[cfg(feature = "log")]
+            self.attach_logs(&TestStatus::Skip);
             let skip_reason_cstring = CString::new(skip_reason_ref).unwrap();
             let mut now = get_now();
             let close_options = topt_TestCloseOptions {
@@ -753,42 +1845,101 @@ impl Test {
         }
     }
 
+    // Helper: the logs buffered by the test logger are only kept for failed tests
+    #[cfg(feature = "log")]
+    fn attach_logs(&self, status: &TestStatus) {
+        if let Some(logs) = take_test_logs(self.test_id) {
+            if matches!(status, TestStatus::Fail) && !logs.is_empty() {
+                self.set_string_tag(tags::TEST_LOGS, logs);
+            }
+        }
+    }
+
     #[allow(dead_code)]
     pub fn set_coverage_data(&self, files: &[impl AsRef<str>]) {
-        unsafe {
-            // Allocate memory for an array of topt_TestCoverageFile
-            let layout = Layout::array::<topt_TestCoverageFile>(files.len()).unwrap();
-            let coverage_file_ptr = alloc(layout) as *mut topt_TestCoverageFile;
-            // Create a vector to hold the CString values so they remain valid
-            let mut cstrings = Vec::with_capacity(files.len());
-            for (idx, file) in files.iter().enumerate() {
-                // Create a CString from the file string
-                let cstr = CString::new(file.as_ref()).unwrap();
-                // Store the CString to keep it alive
-                cstrings.push(cstr);
-                // Get the pointer to the stored CString
-                let filename_ptr = cstrings.last().unwrap().as_ptr() as *mut c_char;
-                *coverage_file_ptr.add(idx) = topt_TestCoverageFile {
-                    filename: filename_ptr,
-                    bitmap: null_mut(),
-                    bitmap_len: 0,
-                };
-            }
+        let report = self.normalize_coverage(files.iter().map(|file| (file.as_ref(), None)));
+        self.send_coverage(&report);
+    }
 
-            let mut coverage_data = topt_TestCoverage {
-                session_id: self.session_id,
-                suite_id: self.suite_id,
-                test_id: self.test_id,
-                files: coverage_file_ptr,
-                files_len: files.len(),
-            };
+    // Sends the executed lines of each file, so test impact analysis can work at line granularity.
+    // Returns the coverage sent after path normalization.
+    #[allow(dead_code)]
+    pub fn set_coverage_lines<K: AsRef<str>>(&self, files: &HashMap<K, CoverageBitmap>) -> CoverageReport {
+        let report = self.normalize_coverage(files.iter().map(|(file, bitmap)| (file.as_ref(), Some(bitmap))));
+        self.send_coverage(&report);
+        report
+    }
+
+    #[allow(dead_code)]
+    pub fn set_coverage_report(&self, report: &CoverageReport) {
+        let report = self.normalize_coverage(report.files().map(|(file, bitmap)| (file, Some(bitmap))));
+        self.send_coverage(&report);
+    }
+
+    // Imports an lcov file produced for this test, returning the coverage sent after path normalization
+    #[allow(dead_code)]
+    pub fn import_lcov_coverage(&self, path: impl AsRef<Path>) -> Result<CoverageReport, CoverageError> {
+        let report = CoverageReport::from_lcov_file(path)?;
+        let report = self.normalize_coverage(report.files().map(|(file, bitmap)| (file, Some(bitmap))));
+        self.send_coverage(&report);
+        Ok(report)
+    }
+
+    // Imports an `llvm-cov export` JSON file produced for this test, returning the coverage sent after path normalization
+    #[cfg(feature = "llvm-cov")]
+    #[allow(dead_code)]
+    pub fn import_llvm_json_coverage(&self, path: impl AsRef<Path>) -> Result<CoverageReport, CoverageError> {
+        let report = CoverageReport::from_llvm_json_file(path)?;
+        let report = self.normalize_coverage(report.files().map(|(file, bitmap)| (file, Some(bitmap))));
+        self.send_coverage(&report);
+        Ok(report)
+    }
 
-            // Send the code coverage payload
-            topt_send_code_coverage_payload(&mut coverage_data, 1);
+    // Helper: normalizes and merges the coverage files, rejecting or tagging the ones outside the repository
+    fn normalize_coverage<'a>(&self, files: impl IntoIterator<Item = (&'a str, Option<&'a CoverageBitmap>)>) -> CoverageReport {
+        let empty_bitmap = CoverageBitmap::new();
+        let normalizer = path_normalizer();
+        let mut report = CoverageReport::new();
+        let mut outside_files = 0;
+        for (file, bitmap) in files {
+            let bitmap = bitmap.unwrap_or(&empty_bitmap);
+            let Some(normalizer) = &normalizer else {
+                report.add_bitmap(file, bitmap);
+                continue;
+            };
+            match normalizer.normalize(file) {
+                NormalizedPath::Repository(file) => report.add_bitmap(file, bitmap),
+                NormalizedPath::Outside(normalized) => match normalizer.policy() {
+                    OutsideRepositoryPolicy::Keep => report.add_bitmap(file, bitmap),
+                    OutsideRepositoryPolicy::Tag => {
+                        outside_files += 1;
+                        report.add_bitmap(normalized, bitmap);
+                    }
+                    OutsideRepositoryPolicy::Reject => {}
+                },
+            }
+        }
+        if outside_files > 0 {
+            self.set_number_tag(tags::TEST_COVERAGE_OUTSIDE_REPOSITORY_FILES, outside_files as f64);
+        }
+        report
+    }
 
-            // Deallocate the memory for the array of topt_TestCoverageFile
-            dealloc(coverage_file_ptr as *mut u8, layout);
-            // The CString objects in `cstrings` are automatically freed when they go out of scope.
+    fn send_coverage(&self, report: &CoverageReport) {
+        let record = TestCoverageRecord {
+            session_id: self.session_id,
+            suite_id: self.suite_id,
+            test_id: self.test_id,
+            files: report
+                .files()
+                .map(|(file, bitmap)| (CString::new(file).unwrap(), bitmap.as_bytes().to_vec()))
+                .collect(),
+        };
+        match coverage_batcher() {
+            Some(batcher) => {
+                batcher.add(record);
+            }
+            None => send_coverage_records(&[record]),
         }
     }
 
@@ -863,8 +2014,7 @@ impl Test {
 
         // Keep keys alive in a vector of CStrings.
         let mut cstrings: Vec<CString> = Vec::with_capacity(num_pairs);
-        let mut i = 0;
-        for (key, &value) in data.iter() {
+        for (i, (key, &value)) in data.iter().enumerate() {
             let key_c = CString::new(key.as_ref()).unwrap();
             let kn = topt_KeyNumberPair {
                 key: key_c.as_ptr() as *mut c_char,
@@ -874,7 +2024,6 @@ impl Test {
                 *kn_array_ptr.add(i) = kn;
             }
             cstrings.push(key_c);
-            i += 1;
         }
         let kn_array = topt_KeyNumberArray {
             data: kn_array_ptr,
@@ -902,8 +2051,11 @@ impl Test {
 pub struct Span {
     pub span_id: u64,
     pub parent_id: u64,
+    trace_id: u64,
 }
 impl Span {
+    // Creates a span under a session, module, suite or test, or under a span entered in this thread or task
+    // (see `Span::enter`). Use `create_child` for spans under other spans, their trace isn't known here.
     pub fn create(
         parent_id: u64,
         operation_name: impl AsRef<str>,
@@ -911,11 +2063,24 @@ impl Span {
         resource_name: impl AsRef<str>,
         span_type: impl AsRef<str>,
     ) -> Self {
+        // Sessions, modules, suites and tests are the root of their own trace
+        let trace_id = context::entered_trace_id(parent_id).unwrap_or(parent_id);
+        Self::start(parent_id, trace_id, operation_name.as_ref(), service_name.as_ref(), resource_name.as_ref(), span_type.as_ref()).0
+    }
 
-        let operation_name_cstring = CString::new(operation_name.as_ref()).unwrap();
-        let service_name_cstring = CString::new(service_name.as_ref()).unwrap();
-        let resource_name_cstring = CString::new(resource_name.as_ref()).unwrap();
-        let span_type_cstring = CString::new(span_type.as_ref()).unwrap();
+    // Helper: creates the span in the parent trace and returns if the parent was valid
+    fn start(
+        parent_id: u64,
+        trace_id: u64,
+        operation_name: &str,
+        service_name: &str,
+        resource_name: &str,
+        span_type: &str,
+    ) -> (Self, bool) {
+        let operation_name_cstring = CString::new(operation_name).unwrap();
+        let service_name_cstring = CString::new(service_name).unwrap();
+        let resource_name_cstring = CString::new(resource_name).unwrap();
+        let span_type_cstring = CString::new(span_type).unwrap();
         let mut now = get_now();
 
         let span_start_options = topt_SpanStartOptions {
@@ -932,7 +2097,113 @@ impl Span {
             topt_span_create(parent_id, span_start_options)
         };
 
-        Self{ span_id: span_result.span_id, parent_id }
+        let valid = Bool_to_bool(span_result.valid);
+        if valid {
+            record_mock_trace(trace_id);
+        }
+        (Self{ span_id: span_result.span_id, parent_id, trace_id }, valid)
+    }
+
+    #[allow(dead_code)]
+    pub fn trace_id(&self) -> u64 {
+        self.trace_id
+    }
+
+    // Creates the span as child of the span or test sending the context, when it belongs to this process
+    // (like a test calling an HTTP server running in the test binary). Returns None for contexts from other
+    // processes: the native tracer assigns the trace ids itself and can't adopt a remote parent.
+    #[allow(dead_code)]
+    pub fn continue_from(
+        context: &TraceContext,
+        operation_name: impl AsRef<str>,
+        service_name: impl AsRef<str>,
+        resource_name: impl AsRef<str>,
+        span_type: impl AsRef<str>,
+    ) -> Option<Self> {
+        // Local traces only use the lower 64 bits
+        if context.trace_id >> 64 != 0 {
+            return None;
+        }
+        let (span, valid) = Self::start(
+            context.parent_id,
+            context.trace_id_low(),
+            operation_name.as_ref(),
+            service_name.as_ref(),
+            resource_name.as_ref(),
+            span_type.as_ref(),
+        );
+        if !valid {
+            return None;
+        }
+        if let Some(tracestate) = &context.tracestate {
+            span.set_string_tag(tags::TRACE_PARENT_TRACESTATE, tracestate);
+        }
+        Some(span)
+    }
+
+    // Context to propagate to child processes and HTTP calls made inside this span
+    #[allow(dead_code)]
+    pub fn trace_context(&self) -> TraceContext {
+        TraceContext::local(self.trace_id, self.span_id)
+    }
+
+    #[allow(dead_code)]
+    pub fn create_child(
+        &self,
+        operation_name: impl AsRef<str>,
+        service_name: impl AsRef<str>,
+        resource_name: impl AsRef<str>,
+        span_type: impl AsRef<str>,
+    ) -> Self {
+        Self::start(
+            self.span_id,
+            self.trace_id,
+            operation_name.as_ref(),
+            service_name.as_ref(),
+            resource_name.as_ref(),
+            span_type.as_ref(),
+        )
+        .0
+    }
+
+    // Runs `f` inside a child span, see `in_span`
+    #[allow(dead_code)]
+    pub fn in_span<R>(&self, operation_name: impl AsRef<str>, f: impl FnOnce(&Span) -> R) -> R {
+        run_in_span(self.span_id, self.trace_id, operation_name.as_ref(), f)
+    }
+
+    // Creates the span under the current test or span (see `Test::enter` and `Span::enter`), if any
+    #[allow(dead_code)]
+    pub fn start_in_current(
+        operation_name: impl AsRef<str>,
+        service_name: impl AsRef<str>,
+        resource_name: impl AsRef<str>,
+        span_type: impl AsRef<str>,
+    ) -> Option<Self> {
+        let parent_id = context::current_parent_id()?;
+        let trace_id = context::current_trace_id()?;
+        let (span, _) = Self::start(
+            parent_id,
+            trace_id,
+            operation_name.as_ref(),
+            service_name.as_ref(),
+            resource_name.as_ref(),
+            span_type.as_ref(),
+        );
+        Some(span)
+    }
+
+    // Makes this span the parent of the spans started in the current context in this thread
+    #[allow(dead_code)]
+    pub fn enter(&self) -> ContextGuard {
+        context::enter(context::current_test_id(), self.span_id, self.trace_id)
+    }
+
+    // Makes this span the parent of the spans started in the current context while the future runs
+    #[cfg(feature = "tokio")]
+    #[allow(dead_code)]
+    pub fn scope<F: Future>(&self, future: F) -> impl Future<Output = F::Output> {
+        context::scope(context::current_test_id(), self.span_id, self.trace_id, future)
     }
 
     #[allow(dead_code)]
@@ -965,7 +2236,7 @@ impl Span {
     ) -> bool {
         let error_type_cstring = CString::new(error_type.as_ref()).unwrap();
         let error_message_cstring = CString::new(error_message.as_ref()).unwrap();
-        let error_stacktrace_cstring = CString::new(error_stacktrace.as_ref()).unwrap();
+        let error_stacktrace_cstring = CString::new(normalize_stacktrace(error_stacktrace.as_ref()).as_ref()).unwrap();
 
         unsafe {
             Bool_to_bool(topt_span_set_error(
@@ -986,6 +2257,48 @@ impl Span {
     }
 }
 
+// Runs `f` inside a new span under the current test or span, or just runs it if there is none.
+// The span is the current one while `f` runs and it's closed on return or panic,
+// a panic sets its error info and keeps unwinding.
+#[allow(dead_code)]
+pub fn in_span<R>(operation_name: impl AsRef<str>, f: impl FnOnce(Option<&Span>) -> R) -> R {
+    match context::current_parent_id().zip(context::current_trace_id()) {
+        Some((parent_id, trace_id)) => run_in_span(parent_id, trace_id, operation_name.as_ref(), |span| f(Some(span))),
+        None => f(None),
+    }
+}
+
+// Helper: creates the span, runs `f` with it as current span and closes it
+fn run_in_span<R>(parent_id: u64, trace_id: u64, operation_name: &str, f: impl FnOnce(&Span) -> R) -> R {
+    let (span, _) = Span::start(parent_id, trace_id, operation_name, "", operation_name, "custom");
+    let result = {
+        let _guard = span.enter();
+        catch_unwind(AssertUnwindSafe(|| f(&span)))
+    };
+    match result {
+        Ok(value) => {
+            span.close();
+            value
+        }
+        Err(payload) => {
+            span.set_error_info("panic", panic_message(payload.as_ref()), "");
+            span.close();
+            resume_unwind(payload)
+        }
+    }
+}
+
+// Helper: message of a panic payload, as set by `panic!` with a literal or a format string
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "Box<dyn Any>"
+    }
+}
+
 /********************************
     Debugging // MockTracer
 *********************************/
@@ -994,8 +2307,15 @@ impl Span {
 pub struct MockTracer;
 
 impl MockTracer {
+    // While tests running in parallel record into a `MockTracer::scoped()`, their spans are kept and the current
+    // spans are only hidden from the unscoped queries and from the scopes of the calling thread
     #[allow(dead_code)]
     pub fn reset() -> bool {
+        if has_mock_scopes() {
+            reset_scoped(Self::all_finished_spans().into_iter().chain(Self::all_open_spans()).map(|span| span.span_id));
+            return true;
+        }
+        clear_hidden_spans();
         unsafe {
             Bool_to_bool(topt_debug_mock_tracer_reset())
         }
@@ -1003,6 +2323,16 @@ impl MockTracer {
 
     #[allow(dead_code)]
     pub fn get_finished_spans() -> Vec<MockSpan> {
+        visible_spans(Self::all_finished_spans())
+    }
+
+    #[allow(dead_code)]
+    pub fn get_open_spans() -> Vec<MockSpan> {
+        visible_spans(Self::all_open_spans())
+    }
+
+    // Spans recorded by the native mock tracer, including the ones hidden by `reset`
+    pub(crate) fn all_finished_spans() -> Vec<MockSpan> {
         unsafe {
             // Get the array from the native side.
             let finished_array = topt_debug_mock_tracer_get_finished_spans();
@@ -1015,8 +2345,7 @@ impl MockTracer {
         }
     }
 
-    #[allow(dead_code)]
-    pub fn get_open_spans() -> Vec<MockSpan> {
+    pub(crate) fn all_open_spans() -> Vec<MockSpan> {
         unsafe {
             // Get the array from the native side.
             let open_array = topt_debug_mock_tracer_get_open_spans();
@@ -1,10 +1,23 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Barrier, Mutex, MutexGuard};
 use std::thread::sleep;
 use std::time::Duration;
+use crate::coverage::{CoverageBatcher, CoverageBitmap, CoverageReport, CoverageSummary, TestCoverageRecord};
+use crate::fixture::Fixture;
+use crate::paths::{NormalizedPath, PathNormalizer};
 use crate::test_optimization::*;
 
+// Sessions opened concurrently share the native library, tests asserting on all the spans or changing the
+// session settings must not run concurrently
+static SESSION_LOCK: Mutex<()> = Mutex::new(());
+
+fn session_lock() -> MutexGuard<'static, ()> {
+    SESSION_LOCK.lock().unwrap_or_else(|e| e.into_inner())
+}
+
 #[test]
 fn it_works() {
+    let _lock = session_lock();
     // session
     let session = TestSession::init_mock();
     println!("Hello, world!");
@@ -113,3 +126,1465 @@ fn it_works() {
         println!("span: {:?}", span);
     }
 }
+
+#[test]
+fn known_tests_contains() {
+    let mut known_tests = KnownTests::new();
+    assert!(known_tests.is_empty());
+    assert!(known_tests.insert("my-test-module", "My Suite", "My PassTest"));
+    assert!(!known_tests.insert("my-test-module", "My Suite", "My PassTest"));
+    known_tests.insert("my-test-module", "My Suite", "My FailTest");
+
+    assert!(known_tests.contains("my-test-module", "My Suite", "My PassTest"));
+    assert!(known_tests.contains("my-test-module", "My Suite", "My FailTest"));
+    assert!(!known_tests.contains("my-test-module", "My Suite", "My NewTest"));
+    assert!(!known_tests.contains("my-test-module", "Other Suite", "My PassTest"));
+    assert!(!known_tests.contains("other-module", "My Suite", "My PassTest"));
+}
+
+#[test]
+fn test_lists_queries() {
+    let known_tests: KnownTests = [
+        ("module-a", "Suite 1", "test_1"),
+        ("module-a", "Suite 1", "test_2"),
+        ("module-a", "Suite 2", "test_3"),
+        ("module-b", "Suite 3", "test_4"),
+    ]
+    .into_iter()
+    .collect();
+    assert_eq!(known_tests.len(), 4);
+    assert_eq!(known_tests.filter_module("module-a").len(), 3);
+    assert_eq!(known_tests.filter_suite("module-a", "Suite 1").len(), 2);
+    assert_eq!(known_tests.tests("module-b", "Suite 3").collect::<Vec<_>>(), vec!["test_4"]);
+
+    let test_management_tests: TestManagementTests = [
+        TestManagementTest {
+            module_name: "module-a".to_string(),
+            suite_name: "Suite 1".to_string(),
+            test_name: "test_2".to_string(),
+            quarantined: true,
+            disabled: false,
+            attempt_to_fix: false,
+        },
+        TestManagementTest {
+            module_name: "module-b".to_string(),
+            suite_name: "Suite 3".to_string(),
+            test_name: "test_4".to_string(),
+            quarantined: false,
+            disabled: true,
+            attempt_to_fix: true,
+        },
+    ]
+    .into_iter()
+    .collect();
+    assert_eq!(test_management_tests.len(), 2);
+    assert_eq!(test_management_tests.quarantined().count(), 1);
+    assert_eq!(test_management_tests.filter_module("module-b").disabled().count(), 1);
+
+    let unmanaged = known_tests.difference(&KnownTests::from(&test_management_tests));
+    assert_eq!(
+        unmanaged.to_string(),
+        "module-a > Suite 1 > test_1\nmodule-a > Suite 2 > test_3\n"
+    );
+    assert_eq!(
+        test_management_tests.to_string(),
+        "module-a > Suite 1 > test_2 [quarantined]\nmodule-b > Suite 3 > test_4 [disabled, attempt_to_fix]\n"
+    );
+    assert_eq!(known_tests.intersection(&unmanaged), unmanaged);
+    assert_eq!(unmanaged.union(&KnownTests::from(&test_management_tests)), known_tests);
+
+    let skippable_tests: SkippableTests = [
+        SkippableTest {
+            suite_name: "Suite 1".to_string(),
+            test_name: "test_1".to_string(),
+            parameters: String::new(),
+            custom_configurations_json: String::new(),
+        },
+        SkippableTest {
+            suite_name: "Suite 1".to_string(),
+            test_name: "test_1".to_string(),
+            parameters: "{\"arguments\":{\"a\":\"1\"}}".to_string(),
+            custom_configurations_json: String::new(),
+        },
+    ]
+    .into_iter()
+    .collect();
+    assert_eq!(skippable_tests.len(), 2);
+    assert!(skippable_tests.contains("Suite 1", "test_1"));
+    assert_eq!(skippable_tests.get("Suite 1", "test_1").len(), 2);
+    assert!(skippable_tests.filter_suite("Suite 2").is_empty());
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn serde_round_trip() {
+    let start_time = std::time::SystemTime::UNIX_EPOCH + Duration::from_nanos(1_700_000_000_123_456_789);
+    let span = MockSpan {
+        span_id: 1,
+        trace_id: 2,
+        parent_span_id: 0,
+        start_time,
+        finish_time: start_time + Duration::from_millis(5),
+        operation_name: "my-operation-name".to_string(),
+        string_tags: HashMap::from([("test.status".to_string(), "pass".to_string())]),
+        number_tags: HashMap::from([("test.number".to_string(), 42f64)]),
+    };
+    let json = serde_json::to_value(&span).unwrap();
+    assert_eq!(json["start_time"], 1_700_000_000_123_456_789u64);
+    assert_eq!(json["finish_time"], 1_700_000_000_128_456_789u64);
+    let deserialized: MockSpan = serde_json::from_value(json).unwrap();
+    assert_eq!(deserialized.start_time, span.start_time);
+    assert_eq!(deserialized.string_tags, span.string_tags);
+
+    let known_tests: KnownTests = [("module-a", "Suite 1", "test_1")].into_iter().collect();
+    let json = serde_json::to_string(&known_tests).unwrap();
+    assert_eq!(json, r#"{"module-a":{"Suite 1":["test_1"]}}"#);
+    assert_eq!(serde_json::from_str::<KnownTests>(&json).unwrap(), known_tests);
+
+    // The test lists are written in name order whatever the hash maps iteration order
+    let known_tests: KnownTests =
+        [("module-b", "Suite 2", "test_2"), ("module-a", "Suite 2", "test_2"), ("module-a", "Suite 2", "test_1"), ("module-a", "Suite 1", "test_3")]
+            .into_iter()
+            .collect();
+    assert_eq!(
+        serde_json::to_string(&known_tests).unwrap(),
+        r#"{"module-a":{"Suite 1":["test_3"],"Suite 2":["test_1","test_2"]},"module-b":{"Suite 2":["test_2"]}}"#
+    );
+    let skippable_tests: SkippableTests = ["test_c", "test_a", "test_b"]
+        .into_iter()
+        .map(|test_name| SkippableTest { suite_name: "Suite 1".to_string(), test_name: test_name.to_string(), ..Default::default() })
+        .collect();
+    let json = serde_json::to_string(&skippable_tests).unwrap();
+    let positions: Vec<_> = ["test_a", "test_b", "test_c"].iter().map(|test_name| json.find(test_name).unwrap()).collect();
+    assert!(positions.is_sorted());
+}
+
+#[cfg(feature = "cache")]
+#[test]
+fn cache_round_trip() {
+    use crate::cache::{CacheKey, TestOptimizationCache};
+
+    let directory = std::env::temp_dir().join(format!("test-optimization-cache-{}", std::process::id()));
+    let key = CacheKey {
+        repository_url: "https://github.com/DataDog/my-repo.git".to_string(),
+        commit_sha: "0123456789abcdef".to_string(),
+        service: "my-repo".to_string(),
+        env: "ci".to_string(),
+    };
+    let cache = TestOptimizationCache::new(&directory, key.clone());
+    let known_tests: KnownTests = [("module-a", "Suite 1", "test_1")].into_iter().collect();
+    assert!(cache.get::<KnownTests>("known_tests").is_none());
+    cache.set("known_tests", &known_tests).unwrap();
+    assert_eq!(cache.get::<KnownTests>("known_tests"), Some(known_tests.clone()));
+
+    // Threads writing the same entry don't share their temporary file
+    std::thread::scope(|scope| {
+        for _ in 0..8 {
+            scope.spawn(|| cache.set("known_tests", &known_tests).unwrap());
+        }
+    });
+    assert_eq!(cache.get::<KnownTests>("known_tests"), Some(known_tests.clone()));
+
+    // Entries for other commits are not visible
+    let other_commit = TestOptimizationCache::new(&directory, CacheKey { commit_sha: "fedcba9876543210".to_string(), ..key });
+    assert!(other_commit.get::<KnownTests>("known_tests").is_none());
+
+    cache.invalidate().unwrap();
+    assert!(cache.get::<KnownTests>("known_tests").is_none());
+    cache.clear().unwrap();
+    assert!(!directory.exists());
+}
+
+#[cfg(feature = "cache")]
+#[test]
+fn cache_expiry_and_failed_responses() {
+    use crate::cache::{CacheKey, TestOptimizationCache};
+
+    let _lock = session_lock();
+    let directory = std::env::temp_dir().join(format!("test-optimization-cache-expiry-{}", std::process::id()));
+    let key = CacheKey {
+        repository_url: "https://github.com/DataDog/my-repo.git".to_string(),
+        commit_sha: "0123456789abcdef".to_string(),
+        service: "my-repo".to_string(),
+        env: "ci".to_string(),
+    };
+    let cache = TestOptimizationCache::new(&directory, key.clone()).with_ttl(Duration::from_secs(60));
+    let known_tests: KnownTests = [("module-a", "Suite 1", "test_1")].into_iter().collect();
+    cache.set("known_tests", &known_tests).unwrap();
+    assert!(cache.get::<KnownTests>("known_tests").is_some());
+
+    // An entry older than the TTL is ignored
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs();
+    let expired = serde_json::json!({ "created_at": now - 61, "key": key, "value": known_tests });
+    std::fs::write(cache.path().join("known_tests.json"), expired.to_string()).unwrap();
+    assert!(cache.get::<KnownTests>("known_tests").is_none());
+    let fresh = serde_json::json!({ "created_at": now - 30, "key": key, "value": known_tests });
+    std::fs::write(cache.path().join("known_tests.json"), fresh.to_string()).unwrap();
+    assert_eq!(cache.get::<KnownTests>("known_tests"), Some(known_tests));
+
+    // The mock backend answers with default settings, like a failed call, which are not cached
+    let session = TestSession::init_mock();
+    session.set_cache(Some(cache.clone()));
+    assert_eq!(session.get_settings(), Settings::default());
+    assert_eq!(session.get_flaky_test_retries_settings(), FlakyTestRetriesSettings::default());
+    assert!(cache.get::<Settings>("settings").is_none());
+    assert!(cache.get::<FlakyTestRetriesSettings>("flaky_test_retries_settings").is_none());
+    session.set_cache(None);
+    session.close(0);
+    cache.clear().unwrap();
+}
+
+#[test]
+fn fixture_replaces_backend_data() {
+    let _lock = session_lock();
+    let fixture = Fixture::new()
+        .with_settings(Settings { known_tests_enabled: true, tests_skipping: true, ..Settings::default() })
+        .with_known_tests([("my-test-module", "My Suite", "My KnownTest")].into_iter().collect())
+        .with_skippable_tests(
+            [SkippableTest {
+                suite_name: "My Suite".to_string(),
+                test_name: "My SkippableTest".to_string(),
+                ..SkippableTest::default()
+            }]
+            .into_iter()
+            .collect(),
+        );
+    let session = TestSession::init_with_fixture(fixture);
+    assert!(session.get_settings().tests_skipping);
+    assert!(session.get_skippable_tests().contains("My Suite", "My SkippableTest"));
+
+    let module = session.create_module("my-test-module", "Framework Name", "Framework Version");
+    let suite = module.create_test_suite("My Suite");
+    let known_test = suite.create_test("My KnownTest");
+    let new_test = suite.create_test("My NewTest");
+    assert!(!known_test.is_new());
+    assert!(new_test.is_new());
+
+    known_test.close(TestStatus::Pass);
+    new_test.close(TestStatus::Pass);
+    suite.close();
+    module.close();
+    session.close(0);
+}
+
+#[test]
+fn parameterized_tests() {
+    let _lock = session_lock();
+    let parameters = TestParameters::new().with_argument("b", "two \"2\"").with_argument("a", 1);
+    assert_eq!(parameters.to_json(), r#"{"arguments":{"a":"1","b":"two \"2\""},"metadata":{}}"#);
+    assert_eq!([("b", "two \"2\""), ("a", "1")].into_iter().collect::<TestParameters>(), parameters);
+
+    let skipped = TestParameters::new().with_argument("a", 1);
+    let fixture = Fixture::new()
+        .with_settings(Settings { known_tests_enabled: true, tests_skipping: true, ..Settings::default() })
+        .with_known_tests([("my-test-module", "My Suite", "my_case")].into_iter().collect())
+        .with_skippable_tests(
+            [SkippableTest {
+                suite_name: "My Suite".to_string(),
+                test_name: "my_case".to_string(),
+                parameters: skipped.to_json(),
+                ..SkippableTest::default()
+            }]
+            .into_iter()
+            .collect(),
+        );
+    let session = TestSession::init_with_fixture(fixture);
+    let module = session.create_module("my-test-module", "Framework Name", "Framework Version");
+    let suite = module.create_test_suite("My Suite");
+    let first = suite.create_parameterized_test("my_case", &skipped);
+    let second = suite.create_parameterized_test("my_case", &TestParameters::new().with_argument("a", 2));
+    let plain = suite.create_test("my_case");
+    assert_eq!(first.parameters(), Some(r#"{"arguments":{"a":"1"},"metadata":{}}"#));
+    assert_eq!(plain.parameters(), None);
+    assert!(first.is_skippable());
+    assert!(!second.is_skippable());
+    assert!(!plain.is_skippable());
+    assert!(!first.is_new() && !second.is_new());
+
+    first.close_with_skip_reason("skipped by test impact analysis");
+    second.close(TestStatus::Pass);
+    plain.close(TestStatus::Pass);
+    let span = MockTracer::get_finished_spans().into_iter().find(|span| span.span_id == second.test_id).unwrap();
+    span.assert_tag_eq("test.parameters", r#"{"arguments":{"a":"2"},"metadata":{}}"#);
+    assert_eq!(span.tag("test.parameters"), second.parameters().map(str::to_string));
+    suite.close();
+    module.close();
+    session.close(0);
+
+    let skippable_tests: SkippableTests = [
+        ("parameterized", r#"{"metadata":{},"arguments":{"a":1}}"#),
+        ("plain", ""),
+        ("empty", "{}"),
+        ("canonical", r#"{"arguments":{},"metadata":{}}"#),
+        ("unreadable", "a=1"),
+    ]
+    .into_iter()
+    .map(|(test_name, parameters)| SkippableTest {
+        suite_name: "My Suite".to_string(),
+        test_name: test_name.to_string(),
+        parameters: parameters.to_string(),
+        ..SkippableTest::default()
+    })
+    .collect();
+    // Parameters are compared as JSON with the serde feature, and as text without it
+    assert_eq!(skippable_tests.contains_parameterized("My Suite", "parameterized", skipped.to_json()), cfg!(feature = "serde"));
+    assert!(skippable_tests.contains_parameterized("My Suite", "parameterized", r#"{"metadata":{},"arguments":{"a":1}}"#));
+    assert!(!skippable_tests.contains_parameterized("My Suite", "parameterized", r#"{"arguments":{"a":"1","b":"2"}}"#));
+    for test_name in ["plain", "empty", "canonical"] {
+        for parameters in ["", "{}", r#"{"arguments":{},"metadata":{}}"#] {
+            assert!(skippable_tests.contains_parameterized("My Suite", test_name, parameters), "{} {}", test_name, parameters);
+        }
+    }
+    assert!(skippable_tests.contains_parameterized("My Suite", "unreadable", "a=1"));
+    assert!(!skippable_tests.contains_parameterized("My Suite", "unreadable", ""));
+
+    // Nothing is skippable when test skipping is disabled
+    let fixture = Fixture::new()
+        .with_settings(Settings { tests_skipping: false, ..Settings::default() })
+        .with_skippable_tests(skippable_tests);
+    let session = TestSession::init_with_fixture(fixture);
+    let module = session.create_module("my-test-module", "Framework Name", "Framework Version");
+    let suite = module.create_test_suite("My Suite");
+    let test = suite.create_test("plain");
+    assert!(!test.is_skippable());
+    test.close(TestStatus::Pass);
+    suite.close();
+    module.close();
+    session.close(0);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_parameters_from_json() {
+    let parameters = TestParameters::new().with_argument("b", "two \"2\"").with_argument("a", 1);
+    assert_eq!(
+        TestParameters::from_json(r#" { "metadata": {}, "arguments": { "b": "two \"2\"", "a": 1 } } "#),
+        Some(parameters)
+    );
+    for empty in ["", "  ", "{}", r#"{"arguments":{}}"#, r#"{"arguments":{},"metadata":{}}"#] {
+        assert_eq!(TestParameters::from_json(empty), Some(TestParameters::new()), "{}", empty);
+    }
+
+    // Escapes and surrogate pairs
+    assert_eq!(
+        TestParameters::from_json(r#"{"arguments":{"text":"\ud83e\udd80 \u00e9\n\t\"\\\/"}}"#),
+        Some(TestParameters::new().with_argument("text", "🦀 é\n\t\"\\/"))
+    );
+    assert_eq!(TestParameters::from_json(r#"{"arguments":{"a":"\ud83e"}}"#), None);
+
+    // Numbers are compared by value, other values as JSON
+    let number = |json: &str| TestParameters::from_json(format!(r#"{{"arguments":{{"a":{}}}}}"#, json));
+    assert_eq!(number("1.0"), number("1"));
+    assert_eq!(number("1"), Some(TestParameters::new().with_argument("a", "1")));
+    assert_eq!(number("-2.50"), Some(TestParameters::new().with_argument("a", "-2.5")));
+    assert_eq!(number("18446744073709551615"), Some(TestParameters::new().with_argument("a", u64::MAX)));
+    assert_eq!(number("true"), Some(TestParameters::new().with_argument("a", "true")));
+    assert_eq!(number(r#"{"y":[1, 2],"x":null}"#), Some(TestParameters::new().with_argument("a", r#"{"x":null,"y":[1,2]}"#)));
+
+    // Not parameters
+    for invalid in [r#"{"arguments":{"a":"1"}"#, r#"{"arguments":[]}"#, r#"{"other":{}}"#, "[]", "a=1"] {
+        assert_eq!(TestParameters::from_json(invalid), None, "{}", invalid);
+    }
+}
+
+#[cfg(feature = "fixture")]
+#[test]
+fn fixture_from_files() {
+    let json = Fixture::from_json_str(
+        r#"{
+            "settings": { "itr_enabled": true, "early_flake_detection": { "enabled": true } },
+            "known_tests": { "my-test-module": { "My Suite": ["My PassTest"] } },
+            "skippable_tests": [{ "suite_name": "My Suite", "test_name": "My SkipTest" }],
+            "test_management_tests": [{ "module_name": "my-test-module", "suite_name": "My Suite", "test_name": "My FailTest", "quarantined": true }]
+        }"#,
+    )
+    .unwrap();
+    let toml = Fixture::from_toml_str(
+        r#"
+            skippable_tests = [{ suite_name = "My Suite", test_name = "My SkipTest" }]
+            test_management_tests = [{ module_name = "my-test-module", suite_name = "My Suite", test_name = "My FailTest", quarantined = true }]
+
+            [settings]
+            itr_enabled = true
+            early_flake_detection = { enabled = true }
+
+            [known_tests.my-test-module]
+            "My Suite" = ["My PassTest"]
+        "#,
+    )
+    .unwrap();
+    for fixture in [json, toml] {
+        assert!(fixture.settings.itr_enabled);
+        assert!(fixture.settings.early_flake_detection.enabled);
+        assert!(fixture.known_tests.contains("my-test-module", "My Suite", "My PassTest"));
+        assert!(fixture.skippable_tests.contains("My Suite", "My SkipTest"));
+        assert_eq!(fixture.test_management_tests.quarantined().count(), 1);
+    }
+}
+
+#[cfg(feature = "fixture")]
+#[test]
+fn fixture_sessions_stay_offline() {
+    let _lock = session_lock();
+    let path = std::env::temp_dir().join(format!("test-optimization-fixture-{}.json", std::process::id()));
+    std::fs::write(&path, "{ not json").unwrap();
+    std::env::set_var(crate::fixture::FIXTURE_ENV, &path);
+    // The invalid file is ignored instead of panicking, its error is kept on the session
+    let session = TestSession::init_mock();
+    std::env::remove_var(crate::fixture::FIXTURE_ENV);
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(session.get_settings(), Settings::default());
+    assert!(matches!(session.fixture_error().as_deref(), Some(crate::fixture::FixtureError::Parse(_))));
+    session.close(0);
+
+    // Fixture sessions record their spans in the mock tracer instead of sending them
+    let session = TestSession::init_with_fixture(
+        Fixture::new().with_settings(Settings { itr_enabled: true, ..Settings::default() }),
+    );
+    assert!(session.get_settings().itr_enabled);
+    let scope = MockTracer::scoped();
+    let module = session.create_module("offline-module", "Framework Name", "Framework Version");
+    module.close();
+    assert!(scope.query().with_tag("test.module", "offline-module").first().is_some());
+    session.close(0);
+}
+
+#[test]
+fn coverage_bitmap_encoding() {
+    let mut bitmap = CoverageBitmap::from_lines([1, 8, 9, 20]);
+    assert_eq!(bitmap.as_bytes(), &[0b1000_0001, 0b1000_0000, 0b0001_0000]);
+    assert!(bitmap.contains(9));
+    assert!(!bitmap.contains(10));
+    assert!(!bitmap.contains(0));
+
+    bitmap.set_line(0);
+    bitmap.set_range(2..=3);
+    assert_eq!(bitmap.lines().collect::<Vec<_>>(), vec![1, 2, 3, 8, 9, 20]);
+
+    let mut merged = CoverageBitmap::from_ranges([30..=32]);
+    merged.union_with(&bitmap);
+    assert_eq!(merged.lines().collect::<Vec<_>>(), vec![1, 2, 3, 8, 9, 20, 30, 31, 32]);
+    assert!(CoverageBitmap::new().is_empty());
+    assert_eq!(CoverageBitmap::from_bytes(vec![0x40]).lines().collect::<Vec<_>>(), vec![2]);
+}
+
+#[test]
+fn coverage_report_from_lcov() {
+    let lcov = "\
+TN:
+SF:/repo/src/lib.rs
+DA:1,1
+DA:2,0
+DA:10,3,abcdef
+end_of_record
+SF:/home/user/.cargo/registry/dep/src/lib.rs
+DA:5,1
+end_of_record
+SF:/repo/src/unused.rs
+DA:3,0
+end_of_record
+";
+    let report = CoverageReport::from_lcov_str(lcov).unwrap();
+    assert_eq!(report.len(), 2);
+    let report = report.relative_to("/repo");
+    assert_eq!(report.files().map(|(file, _)| file).collect::<Vec<_>>(), vec!["src/lib.rs"]);
+    assert_eq!(report.get("src/lib.rs").unwrap().lines().collect::<Vec<_>>(), vec![1, 10]);
+
+    assert!(CoverageReport::from_lcov_str("DA:1,1\n").is_err());
+    assert!(CoverageReport::from_lcov_str("SF:a.rs\nDA:x,1\n").is_err());
+}
+
+#[cfg(feature = "llvm-cov")]
+#[test]
+fn coverage_report_from_llvm_json() {
+    // The region starting on line 2 is never executed and wraps the start of line 3
+    let json = r#"{
+        "type": "llvm.coverage.json.export",
+        "version": "2.0.1",
+        "data": [{
+            "files": [{
+                "filename": "/repo/src/main.rs",
+                "segments": [
+                    [1, 11, 1, true, true, false],
+                    [2, 14, 0, true, true, false],
+                    [3, 6, 1, true, false, false],
+                    [5, 2, 0, false, false, false]
+                ]
+            }]
+        }]
+    }"#;
+    let report = CoverageReport::from_llvm_json_str(json).unwrap().relative_to("/repo");
+    assert_eq!(report.get("src/main.rs").unwrap().lines().collect::<Vec<_>>(), vec![1, 2, 4, 5]);
+    assert!(CoverageReport::from_llvm_json_str("{}").is_err());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_coverage_lines() {
+    let _lock = session_lock();
+    let session = TestSession::init_mock_with_working_dir("/repo");
+    session.set_path_normalizer(Some(PathNormalizer::new("/repo", "/repo")));
+    session.set_coverage_batcher(Some(CoverageBatcher::new(10)));
+    let batcher = session.get_coverage_batcher().unwrap();
+    let module = session.create_module("my-test-module", "Framework Name", "Framework Version");
+    let suite = module.create_test_suite("My Suite");
+    let test = suite.create_test("My CoveredTest");
+
+    let report = test.set_coverage_lines(&HashMap::from([
+        ("/repo/src/lib.rs", CoverageBitmap::from_ranges([6..=8])),
+        ("./src/lib.rs", CoverageBitmap::from_lines([20])),
+        ("src/main.rs", CoverageBitmap::from_lines([1])),
+    ]));
+    // Paths are normalized and the bitmaps of the same file merged before they are queued
+    assert_eq!(report.len(), 2);
+    assert_eq!(report.get("src/lib.rs").unwrap().lines().collect::<Vec<_>>(), vec![6, 7, 8, 20]);
+    assert_eq!(report.get("src/lib.rs").unwrap().as_bytes(), &[0b0000_0111, 0b0000_0000, 0b0001_0000]);
+    assert_eq!(report.get("src/main.rs").unwrap().as_bytes(), &[0b1000_0000]);
+    assert_eq!(batcher.len(), 1);
+
+    test.close(TestStatus::Pass);
+    suite.close();
+    module.close();
+    session.close(0);
+}
+
+#[test]
+fn coverage_batching() {
+    let _lock = session_lock();
+    let session = TestSession::init_mock();
+    session.set_coverage_batcher(Some(CoverageBatcher::new(3)));
+    let batcher = session.get_coverage_batcher().unwrap();
+
+    let module = session.create_module("my-test-module", "Framework Name", "Framework Version");
+    let suite = module.create_test_suite("My Suite");
+    let tests: Vec<Test> = (0..4).map(|i| suite.create_test(format!("My Test {}", i))).collect();
+    tests[0].set_coverage_data(&["file.rs"]);
+    tests[1].set_coverage_lines(&HashMap::from([("file.rs", CoverageBitmap::from_lines([1, 2]))]));
+    assert_eq!(batcher.len(), 2);
+    // The third test fills the batch, so it's sent
+    tests[2].set_coverage_data(&["file.rs", "other.rs"]);
+    assert!(batcher.is_empty());
+    tests[3].set_coverage_data(&["file.rs"]);
+    assert_eq!(batcher.len(), 1);
+
+    for test in &tests {
+        test.close(TestStatus::Pass);
+    }
+    suite.close();
+    module.close();
+    session.close(0);
+    assert!(batcher.is_empty());
+    assert!(batcher.is_closed());
+    assert!(session.get_coverage_batcher().is_none());
+
+    // A clone kept past the session drops the records, nothing is left to send on drop
+    assert!(!batcher.add(TestCoverageRecord { session_id: 0, suite_id: 0, test_id: 0, files: Vec::new() }));
+    assert!(batcher.is_empty());
+    drop(batcher);
+}
+
+#[cfg(unix)]
+#[test]
+fn path_normalization() {
+    let normalizer = PathNormalizer::new("/repo", "/repo/crates/api");
+    assert_eq!(normalizer.normalize("src/lib.rs"), NormalizedPath::Repository("crates/api/src/lib.rs".to_string()));
+    assert_eq!(normalizer.normalize("./src/../tests/it.rs"), NormalizedPath::Repository("crates/api/tests/it.rs".to_string()));
+    assert_eq!(normalizer.normalize("/repo/src/main.rs"), NormalizedPath::Repository("src/main.rs".to_string()));
+    assert_eq!(normalizer.normalize("src\\windows.rs"), NormalizedPath::Repository("crates/api/src/windows.rs".to_string()));
+    assert_eq!(
+        normalizer.normalize("/home/user/.cargo/registry/dep/src/lib.rs"),
+        NormalizedPath::Outside("/home/user/.cargo/registry/dep/src/lib.rs".to_string())
+    );
+    assert_eq!(normalizer.normalize("../../../outside.rs"), NormalizedPath::Outside("/outside.rs".to_string()));
+
+    let stacktrace = "   0: my_crate::my_test\n             at /repo/crates/api/src/lib.rs:10:5\n   1: core::ops::function::FnOnce::call_once\n             at /rustc/library/core/src/ops/function.rs:250:5";
+    assert_eq!(
+        normalizer.normalize_stacktrace(stacktrace),
+        "   0: my_crate::my_test\n             at crates/api/src/lib.rs:10:5\n   1: core::ops::function::FnOnce::call_once\n             at /rustc/library/core/src/ops/function.rs:250:5"
+    );
+
+    // A root which is also the end of other paths only matches at the start of a path
+    let normalizer = PathNormalizer::new("/src", "/src");
+    let stacktrace = "/src/main.rs:1:1\n  at /rustc/abc/library/core/src/ops/function.rs:250:5\n  at (/src/lib.rs:3) file='/src/a.rs' path=/src/b.rs";
+    assert_eq!(
+        normalizer.normalize_stacktrace(stacktrace),
+        "main.rs:1:1\n  at /rustc/abc/library/core/src/ops/function.rs:250:5\n  at (lib.rs:3) file='a.rs' path=b.rs"
+    );
+    let unrelated = "at /rustc/abc/library/core/src/ops/function.rs:250:5";
+    assert!(matches!(normalizer.normalize_stacktrace(unrelated), std::borrow::Cow::Borrowed(_)));
+}
+
+#[cfg(unix)]
+#[test]
+fn outside_repository_policies() {
+    use crate::paths::OutsideRepositoryPolicy;
+
+    let _lock = session_lock();
+    let session = TestSession::init_mock_with_working_dir("/repo");
+    let module = session.create_module("my-test-module", "Framework Name", "Framework Version");
+    let suite = module.create_test_suite("My Suite");
+    let test = suite.create_test("My OutsideTest");
+    let source_tags = || {
+        let span = MockTracer::get_open_spans().into_iter().find(|span| span.span_id == test.test_id).unwrap();
+        (span.tag("test.source.file"), span.tag("test.source.outside_repository"))
+    };
+
+    // Outside files are passed through by default
+    session.set_path_normalizer(Some(PathNormalizer::new("/repo", "/repo")));
+    assert!(test.set_test_source("../dep/src/lib.rs", &1, &2));
+    assert_eq!(source_tags(), (Some("../dep/src/lib.rs".to_string()), None));
+    assert_eq!(test.set_coverage_lines(&HashMap::from([("/dep/lib.rs", CoverageBitmap::from_lines([1]))])).len(), 1);
+
+    session.set_path_normalizer(Some(PathNormalizer::new("/repo", "/repo").with_policy(OutsideRepositoryPolicy::Tag)));
+    assert!(test.set_test_source("../dep/src/lib.rs", &1, &2));
+    assert_eq!(source_tags(), (Some("/dep/src/lib.rs".to_string()), Some("true".to_string())));
+
+    session.set_path_normalizer(Some(PathNormalizer::new("/repo", "/repo").with_policy(OutsideRepositoryPolicy::Reject)));
+    assert!(!test.set_test_source("/other/lib.rs", &1, &2));
+    assert!(test.set_coverage_lines(&HashMap::from([("/dep/lib.rs", CoverageBitmap::from_lines([1]))])).is_empty());
+
+    test.close(TestStatus::Pass);
+    suite.close();
+    module.close();
+    session.close(0);
+}
+
+#[test]
+fn coverage_summary_from_lcov() {
+    let lcov = "\
+SF:src/lib.rs
+DA:1,1
+DA:2,0
+LF:4
+LH:3
+end_of_record
+SF:src/main.rs
+DA:1,2
+DA:2,0
+DA:3,0
+DA:4,1
+end_of_record
+";
+    let summary = CoverageSummary::from_lcov_str(lcov).unwrap();
+    assert_eq!(summary, CoverageSummary { lines_found: 8, lines_hit: 5 });
+    assert_eq!(summary.percentage(), 62.5);
+    assert_eq!(CoverageSummary::default().percentage(), 0.0);
+    assert!(CoverageSummary::from_lcov_str("LF:many\n").is_err());
+}
+
+#[test]
+fn session_total_coverage() {
+    let _lock = session_lock();
+    let session = TestSession::init_mock();
+    let lines_pct = || {
+        let span = MockTracer::get_open_spans().into_iter().find(|span| span.span_id == session.session_id).unwrap();
+        span.number_tags.get("test.code_coverage.lines_pct").copied()
+    };
+    assert!(session.report_total_coverage(87.5));
+    assert_eq!(lines_pct(), Some(87.5));
+    assert!(session.report_total_coverage(120.0));
+    assert_eq!(lines_pct(), Some(100.0));
+    assert!(!session.report_total_coverage(f64::NAN));
+    assert!(!session.report_total_coverage(f64::INFINITY));
+    assert_eq!(lines_pct(), Some(100.0));
+
+    let path = std::env::temp_dir().join(format!("test-optimization-total-{}.lcov", std::process::id()));
+    std::fs::write(&path, "SF:src/lib.rs\nLF:4\nLH:1\nend_of_record\n").unwrap();
+    assert_eq!(session.import_total_coverage_lcov(&path).unwrap(), 25.0);
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(lines_pct(), Some(25.0));
+    session.close(0);
+}
+
+#[cfg(feature = "tracing")]
+#[test]
+fn tracing_layer_spans() {
+    use crate::tracing_layer::TestOptimizationLayer;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    let _lock = session_lock();
+    let session = TestSession::init_mock();
+    MockTracer::reset();
+    let module = session.create_module("tracing-module", "Framework Name", "Framework Version");
+    let suite = module.create_test_suite("tracing-suite");
+    let test = suite.create_test("tracing-test");
+
+    let subscriber = tracing_subscriber::registry().with(TestOptimizationLayer::new());
+    tracing::subscriber::with_default(subscriber, || {
+        // Outside of a test the spans are ignored
+        tracing::info_span!("tracing-outside").in_scope(|| {});
+
+        let _guard = test.enter();
+        tracing::info_span!("tracing-outer", user = "alice", retries = 3).in_scope(|| {
+            tracing::info_span!("tracing-inner").in_scope(|| {
+                tracing::error!(error.type = "MyError", "something failed");
+            });
+        });
+    });
+
+    test.close(TestStatus::Pass);
+    suite.close();
+    module.close();
+    let spans = MockTracer::get_finished_spans();
+    session.close(0);
+
+    let find = |name: &str| spans.iter().find(|span| span.operation_name == name);
+    assert!(find("tracing-outside").is_none());
+    let outer = find("tracing-outer").expect("outer span");
+    assert_eq!(outer.parent_span_id, test.test_id);
+    assert_eq!(outer.string_tags.get("user").map(String::as_str), Some("alice"));
+    assert_eq!(outer.number_tags.get("retries"), Some(&3.0));
+    let inner = find("tracing-inner").expect("inner span");
+    assert_eq!(inner.parent_span_id, outer.span_id);
+    assert_eq!(inner.string_tags.get("error.type").map(String::as_str), Some("MyError"));
+    assert_eq!(inner.string_tags.get("error.message").map(String::as_str), Some("something failed"));
+}
+
+#[cfg(feature = "log")]
+#[test]
+fn test_logger_attaches_logs_to_failed_tests() {
+    use crate::tags;
+    use crate::test_logger::{take_test_logs, TestLogger};
+    use log::LevelFilter;
+
+    let _lock = session_lock();
+    TestLogger::new().with_level(LevelFilter::Info).with_max_size(40).init().unwrap();
+    let session = TestSession::init_mock();
+    MockTracer::reset();
+    let module = session.create_module("log-module", "Framework Name", "Framework Version");
+    let suite = module.create_test_suite("log-suite");
+
+    let failed = suite.create_test("log-failed-test");
+    {
+        let _guard = failed.enter();
+        for i in 1..=3 {
+            log::info!(target: "app", "line {}", i);
+        }
+        log::debug!(target: "app", "filtered");
+    }
+    log::info!(target: "app", "outside of a test");
+    failed.close(TestStatus::Fail);
+
+    let passed = suite.create_test("log-passed-test");
+    {
+        let _guard = passed.enter();
+        log::error!(target: "app", "not attached");
+    }
+    passed.close(TestStatus::Pass);
+
+    // Buffers are dropped on every close path, and records after close don't recreate them
+    let skipped = suite.create_test("log-skipped-test");
+    let unclosed = suite.create_test("log-unclosed-test");
+    for test in [&skipped, &unclosed] {
+        let _guard = test.enter();
+        log::info!(target: "app", "buffered");
+    }
+    skipped.close_with_skip_reason("not today");
+    {
+        let _guard = skipped.enter();
+        log::info!(target: "app", "after close");
+    }
+    assert_eq!(take_test_logs(skipped.test_id), None);
+
+    suite.close();
+    module.close();
+    let spans = MockTracer::get_finished_spans();
+    session.close(0);
+    // Tests never closed lose their buffer with the session
+    assert_eq!(take_test_logs(unclosed.test_id), None);
+
+    let logs = |test: &Test| {
+        spans.iter().find(|span| span.span_id == test.test_id).and_then(|span| span.string_tags.get(tags::TEST_LOGS).cloned())
+    };
+    assert_eq!(logs(&failed).as_deref(), Some("... 1 earlier log lines dropped\nINFO app: line 2\nINFO app: line 3"));
+    assert_eq!(logs(&passed), None);
+}
+
+#[test]
+fn current_context_spans() {
+    use crate::context::{current_parent_id, current_test_id};
+
+    let _lock = session_lock();
+    let session = TestSession::init_mock();
+    MockTracer::reset();
+    let module = session.create_module("context-module", "Framework Name", "Framework Version");
+    let suite = module.create_test_suite("context-suite");
+    let test = suite.create_test("context-test");
+
+    assert!(Span::start_in_current("context-no-parent", "", "", "custom").is_none());
+    let (outer, inner) = {
+        let _test_guard = test.enter();
+        let outer = Span::start_in_current("context-outer", "", "", "custom").unwrap();
+        let inner = {
+            let _span_guard = outer.enter();
+            assert_eq!(current_parent_id(), Some(outer.span_id));
+            assert_eq!(current_test_id(), Some(test.test_id));
+            Span::start_in_current("context-inner", "", "", "custom").unwrap()
+        };
+        assert_eq!(current_parent_id(), Some(test.test_id));
+        inner.close();
+        outer.close();
+        (outer, inner)
+    };
+    assert_eq!(current_parent_id(), None);
+    assert_eq!(outer.parent_id, test.test_id);
+    assert_eq!(inner.parent_id, outer.span_id);
+
+    #[cfg(feature = "tokio")]
+    {
+        use crate::context::in_current_context;
+
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let (task_span, spawned_span) = runtime.block_on(test.scope(async {
+            let task_span = Span::start_in_current("context-task", "", "", "custom").unwrap();
+            let spawned_span = tokio::spawn(in_current_context(task_span.scope(async {
+                tokio::task::yield_now().await;
+                Span::start_in_current("context-spawned", "", "", "custom").unwrap()
+            })))
+            .await
+            .unwrap();
+            (task_span, spawned_span)
+        }));
+        assert_eq!(task_span.parent_id, test.test_id);
+        assert_eq!(spawned_span.parent_id, task_span.span_id);
+        spawned_span.close();
+        task_span.close();
+    }
+
+    test.close(TestStatus::Pass);
+    suite.close();
+    module.close();
+    let spans = MockTracer::get_finished_spans();
+    session.close(0);
+
+    let find = |name: &str| spans.iter().find(|span| span.operation_name == name).unwrap();
+    assert_eq!(find("context-outer").parent_span_id, test.test_id);
+    assert_eq!(find("context-inner").parent_span_id, outer.span_id);
+}
+
+#[test]
+fn nested_spans() {
+    let _lock = session_lock();
+    let session = TestSession::init_mock();
+    MockTracer::reset();
+    let module = session.create_module("nested-module", "Framework Name", "Framework Version");
+    let suite = module.create_test_suite("nested-suite");
+    let test = suite.create_test("nested-test");
+
+    assert!(in_span("nested-no-parent", |span| span.is_none()));
+    let (first, second, third) = test.in_span("nested-first", |first| {
+        let (second, third) = in_span("nested-second", |second| {
+            let second = second.unwrap().clone();
+            let third = second.create_child("nested-third", "my-service", "nested-resource", "custom");
+            third.close();
+            (second, third)
+        });
+        (first.clone(), second, third)
+    });
+
+    let panicked = std::panic::catch_unwind(|| {
+        test.in_span("nested-panic", |span| span.in_span("nested-panic-child", |_| panic!("boom {}", 42)))
+    });
+    assert!(panicked.is_err());
+
+    test.close(TestStatus::Pass);
+    suite.close();
+    module.close();
+    let spans = MockTracer::get_finished_spans();
+    let open_spans = MockTracer::get_open_spans();
+    session.close(0);
+
+    let find = |name: &str| spans.iter().find(|span| span.operation_name == name).unwrap();
+    assert_eq!(find("nested-first").parent_span_id, test.test_id);
+    assert_eq!(find("nested-second").parent_span_id, first.span_id);
+    assert_eq!(find("nested-third").parent_span_id, second.span_id);
+    assert_eq!(find("nested-third").span_id, third.span_id);
+    assert_eq!(find("nested-third").trace_id, find("nested-first").trace_id);
+
+    let panic_span = find("nested-panic");
+    let panic_child = find("nested-panic-child");
+    assert_eq!(panic_child.parent_span_id, panic_span.span_id);
+    for span in [panic_span, panic_child] {
+        assert_eq!(span.string_tags.get("error.type").map(String::as_str), Some("panic"));
+        assert_eq!(span.string_tags.get("error.message").map(String::as_str), Some("boom 42"));
+    }
+    assert!(open_spans.iter().all(|span| !span.operation_name.starts_with("nested-")));
+}
+
+#[test]
+fn trace_context_propagation() {
+    use crate::propagation::TraceContext;
+
+    let context = TraceContext::from_traceparent("00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01").unwrap();
+    assert_eq!(context.trace_id, 0x0af7651916cd43dd8448eb211c80319c);
+    assert_eq!(context.parent_id, 0xb7ad6b7169203331);
+    assert!(context.sampled);
+    assert_eq!(context.to_traceparent(), "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01");
+    assert!(TraceContext::from_traceparent("00-00000000000000000000000000000000-b7ad6b7169203331-01").is_none());
+    assert!(TraceContext::from_traceparent("ff-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01").is_none());
+    assert!(TraceContext::from_traceparent("00-0af7651916cd43dd8448eb211c80319c-b7ad6b71692033-01").is_none());
+    let headers = [("TraceParent", "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-00"), ("tracestate", "a=1"), ("tracestate", "b=2")];
+    let context = TraceContext::from_headers(headers).unwrap();
+    assert!(!context.sampled);
+    assert_eq!(context.tracestate.as_deref(), Some("a=1,b=2"));
+
+    let _lock = session_lock();
+    let session = TestSession::init_mock();
+    let module = session.create_module("propagation-module", "Framework Name", "Framework Version");
+    let suite = module.create_test_suite("propagation-suite");
+    let test = suite.create_test("propagation-test");
+
+    // A client span propagates its context to a server running in the same process
+    let client = Span::create(test.test_id, "propagation-client", "", "", "http");
+    let env_vars = client.trace_context().to_env_vars();
+    assert_eq!(env_vars[0], ("TRACEPARENT", format!("00-{:032x}-{:016x}-01", test.test_id, client.span_id)));
+    let headers = client.trace_context().to_headers();
+    let incoming = TraceContext::from_headers(headers.iter().map(|(name, value)| (*name, value.as_str()))).unwrap();
+    let server = Span::continue_from(&incoming, "propagation-server", "", "", "web").unwrap();
+    assert_eq!((server.parent_id, server.trace_id()), (incoming.parent_id, incoming.trace_id_low()));
+    let nested = server.create_child("propagation-nested", "", "", "custom");
+    assert_eq!(nested.trace_id(), test.test_id);
+    nested.close();
+    server.close();
+    // Children keep the trace of their parent after it's closed
+    let late = server.create_child("propagation-late", "", "", "custom");
+    assert_eq!(late.trace_id(), test.test_id);
+    assert_eq!(late.trace_context().trace_id_low(), test.test_id);
+    late.close();
+    // `Span::create` finds the trace of an entered span
+    let entered = {
+        let _guard = client.enter();
+        Span::create(client.span_id, "propagation-entered", "", "", "custom")
+    };
+    assert_eq!(entered.trace_id(), test.test_id);
+    entered.close();
+    client.close();
+
+    // The native tracer can't continue contexts from other processes
+    let remote = {
+        let _guard = test.enter();
+        Span::continue_from(&context, "propagation-remote", "", "", "web")
+    };
+    assert!(remote.is_none());
+
+    let test_context = test.trace_context();
+    assert_eq!(test_context.trace_id_low(), test.test_id);
+    assert_eq!(test_context.parent_id, test.test_id);
+
+    test.close(TestStatus::Pass);
+    suite.close();
+    module.close();
+    let spans = MockTracer::get_finished_spans();
+    session.close(0);
+
+    let find = |name: &str| spans.iter().find(|span| span.operation_name == name).unwrap();
+    let server = find("propagation-server");
+    assert_eq!(server.trace_id, incoming.trace_id_low());
+    assert_eq!(server.parent_span_id, incoming.parent_id);
+    server.assert_tag_eq("trace.parent.tracestate", format!("dd=s:1;p:{:016x}", incoming.parent_id));
+    for name in ["propagation-nested", "propagation-late", "propagation-entered"] {
+        assert_eq!(find(name).trace_id, incoming.trace_id_low());
+    }
+    assert_eq!(find("propagation-entered").parent_span_id, incoming.parent_id);
+    assert!(spans.iter().all(|span| span.operation_name != "propagation-remote"));
+}
+
+#[test]
+fn mock_tracer_queries() {
+    let _lock = session_lock();
+    let session = TestSession::init_mock();
+    MockTracer::reset();
+    let module = session.create_module("query-module", "Framework Name", "Framework Version");
+    let suite = module.create_test_suite("query-suite");
+    let test = suite.create_test("query-test");
+    let span = Span::create(test.test_id, "query-span", "query-service", "query-resource", "custom");
+    span.set_string_tag("query.key", "value");
+    span.set_number_tag("query.number", 42f64);
+    let child = span.create_child("query-child", "", "", "custom");
+    child.close();
+    span.close();
+    test.close(TestStatus::Fail);
+    suite.close();
+    module.close();
+
+    let query = MockTracer::query();
+    assert_eq!(query.clone().by_operation("query-span").with_tag("query.key", "value").len(), 1);
+    assert_eq!(query.clone().with_tag("query.number", "42").first().map(|span| span.span_id), Some(span.span_id));
+    assert!(query.clone().with_tag("query.key", "other").is_empty());
+    assert_eq!(query.clone().children_of(span.span_id).first().map(|span| span.span_id), Some(child.span_id));
+    assert_eq!(query.clone().in_trace(test.test_id).len(), 3);
+
+    MockTracer::assert_span_exists("query-span").assert_tag_eq("query.key", "value").assert_tag_eq("query.number", "42");
+    query.clone().with_tag("test.name", "query-test").first().unwrap().assert_status(TestStatus::Fail);
+    MockTracer::assert_no_open_spans();
+
+    let message = |result: std::thread::Result<()>| {
+        let payload = result.unwrap_err();
+        payload.downcast_ref::<String>().cloned().unwrap_or_default()
+    };
+    let mismatch = message(std::panic::catch_unwind(|| {
+        MockTracer::assert_span_exists("query-span").assert_tag_eq("query.key", "other");
+    }));
+    assert!(mismatch.contains("expected: `other`\n     found: `value`"), "{}", mismatch);
+    assert!(mismatch.contains("    query.number = 42\n"), "{}", mismatch);
+    let missing = message(std::panic::catch_unwind(|| {
+        query.clone().by_operation("query-child").assert_span_exists("query-missing");
+    }));
+    assert!(missing.contains(&format!("`query-child` [custom] span_id={}", child.span_id)), "{}", missing);
+
+    let open_span = Span::create(test.test_id, "query-open", "", "", "custom");
+    let open = message(std::panic::catch_unwind(MockTracer::assert_no_open_spans));
+    assert!(open.starts_with("1 spans are still open:\n  - `query-open`"), "{}", open);
+    open_span.close();
+    session.close(0);
+}
+
+#[test]
+fn span_tree_from_mock_spans() {
+    use crate::mock_tracer::SpanTree;
+    use std::time::SystemTime;
+
+    let _lock = session_lock();
+    let session = TestSession::init_mock();
+    let module = session.create_module("tree-module", "Framework Name", "Framework Version");
+    let suite = module.create_test_suite("tree-suite");
+    let test = suite.create_test("tree-test");
+    let span = Span::create(test.test_id, "tree-span", "", "", "custom");
+    let child = span.create_child("tree-child", "", "", "custom");
+    child.close();
+    span.close();
+    test.close(TestStatus::Pass);
+
+    let tree = SpanTree::capture();
+    let session_node = tree.find(session.session_id).unwrap();
+    assert!(session_node.open);
+    let module_node = session_node.children.iter().find(|node| node.span.span_id == module.module_id).unwrap();
+    let suite_node = &module_node.children[0];
+    let test_node = &suite_node.children[0];
+    assert_eq!(test_node.span.span_id, test.test_id);
+    assert_eq!(test_node.children[0].span.span_id, span.span_id);
+    assert_eq!(test_node.children[0].children[0].span.span_id, child.span_id);
+    // Other tests may leave spans behind, only check the ones of this test
+    let span_ids = [session.session_id, module.module_id, suite.suite_id, test.test_id, span.span_id, child.span_id];
+    assert!(tree.orphans().iter().all(|(span_id, _)| !span_ids.contains(span_id)));
+    assert!(tree.cycles().is_empty());
+
+    let rendered = tree.to_string();
+    let suite_line = rendered.lines().find(|line| line.contains("test.suite=tree-suite") && !line.contains("test.name")).unwrap();
+    assert!(suite_line.starts_with("    ") && suite_line.ends_with("open test.suite=tree-suite"), "{}", rendered);
+    assert!(rendered.contains("\n        tree-span [custom] "), "{}", rendered);
+    assert!(rendered.contains("\n          tree-child [custom] "), "{}", rendered);
+
+    suite.close();
+    module.close();
+    session.close(0);
+
+    let mock_span = |span_id: u64, parent_span_id: u64| MockSpan {
+        span_id,
+        trace_id: 1,
+        parent_span_id,
+        start_time: SystemTime::UNIX_EPOCH,
+        finish_time: SystemTime::UNIX_EPOCH,
+        operation_name: format!("span-{}", span_id),
+        string_tags: HashMap::new(),
+        number_tags: HashMap::new(),
+    };
+    let tree = SpanTree::from_spans(vec![mock_span(1, 0), mock_span(2, 99), mock_span(3, 4), mock_span(4, 3), mock_span(5, 4)], vec![]);
+    assert_eq!(tree.orphans(), &[(2, 99)]);
+    assert_eq!(tree.cycles(), &[vec![3, 4]]);
+    assert_eq!(
+        tree.to_string(),
+        "span-1 0ns\nspan-2 0ns (orphan, parent 99 not found)\nspan-3 0ns\n  span-4 0ns\n    span-5 0ns\ncycle: 3 -> 4 -> 3\n"
+    );
+}
+
+#[test]
+fn mock_span_snapshots() {
+    use crate::mock_tracer::{SnapshotTimestamps, SpanSnapshot};
+    use std::time::SystemTime;
+
+    let mock_span = |span_id: u64, parent_span_id: u64, start_ms: u64, tags: &[(&str, &str)]| MockSpan {
+        span_id,
+        trace_id: if parent_span_id == 0 { span_id } else { 7000 },
+        parent_span_id,
+        start_time: SystemTime::UNIX_EPOCH + Duration::from_millis(start_ms),
+        finish_time: SystemTime::UNIX_EPOCH + Duration::from_millis(start_ms + 5),
+        operation_name: format!("op-{}", span_id),
+        string_tags: tags.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect(),
+        number_tags: HashMap::from([("test_suite_id".to_string(), 7000f64)]),
+    };
+    let spans = vec![
+        mock_span(7020, 7000, 1010, &[("custom.tag", "value"), ("os.platform", "linux")]),
+        mock_span(7000, 0, 1000, &[("test.name", "my-test"), ("error.stack", "at src/lib.rs:1"), ("secret", "token")]),
+    ];
+
+    let snapshot = SpanSnapshot::new(spans.clone()).redact_tag("secret");
+    let expected = "\
+- span: 1
+  trace: 1
+  parent: 0
+  operation: \"op-7000\"
+  tags:
+    error.stack: [redacted]
+    secret: [redacted]
+    test.name: \"my-test\"
+    test_suite_id: 1
+- span: 2
+  trace: 1
+  parent: 1
+  operation: \"op-7020\"
+  tags:
+    custom.tag: \"value\"
+    os.platform: [redacted]
+    test_suite_id: 1
+";
+    assert_eq!(snapshot.to_string(), expected);
+    let relative = SpanSnapshot::new(spans.clone()).with_timestamps(SnapshotTimestamps::Relative).to_string();
+    assert!(relative.contains("  operation: \"op-7020\"\n  start: 10ms\n  duration: 5ms\n"), "{}", relative);
+
+    let folder = std::env::temp_dir().join(format!("topt-snapshot-{}", std::process::id()));
+    std::fs::create_dir_all(&folder).unwrap();
+    let golden = folder.join("spans.snap");
+    std::fs::write(&golden, expected).unwrap();
+    snapshot.assert_matches(&golden);
+
+    std::fs::write(&golden, expected.replace("\"value\"", "\"old\"")).unwrap();
+    let mismatch = std::panic::catch_unwind(|| snapshot.assert_matches(&golden)).unwrap_err();
+    let message = mismatch.downcast_ref::<String>().unwrap();
+    assert!(message.contains("\n-    custom.tag: \"old\"\n+    custom.tag: \"value\"\n     os.platform: [redacted]\n"), "{}", message);
+    std::fs::remove_dir_all(&folder).unwrap();
+}
+
+#[test]
+fn scoped_mock_tracer() {
+    let _lock = session_lock();
+    let session = TestSession::init_mock();
+    let module = session.create_module("scoped-module", "Framework Name", "Framework Version");
+    let suite = module.create_test_suite("scoped-suite");
+
+    std::thread::scope(|threads| {
+        for name in ["scoped-a", "scoped-b"] {
+            let suite = &suite;
+            threads.spawn(move || {
+                let scope = MockTracer::scoped();
+                let test = suite.create_test(name);
+                let span = Span::create(test.test_id, format!("{}-span", name), "", "", "custom");
+                // Child spans from other threads belong to the trace of the test
+                let child = std::thread::scope(|inner| inner.spawn(|| span.create_child(format!("{}-child", name), "", "", "custom")).join().unwrap());
+                child.close();
+                span.close();
+                test.close(TestStatus::Pass);
+
+                assert_eq!(scope.trace_ids(), HashSet::from([test.test_id]));
+                let mut operations: Vec<String> = scope.get_finished_spans().into_iter().map(|span| span.operation_name).collect();
+                operations.sort();
+                assert_eq!(operations.len(), 3);
+                assert!(operations.contains(&format!("{}-span", name)) && operations.contains(&format!("{}-child", name)));
+                assert_eq!(scope.query().with_tag("test.name", name).len(), 1);
+                assert!(scope.get_open_spans().is_empty());
+            });
+        }
+    });
+
+    let scope = MockTracer::scoped();
+    assert!(scope.get_finished_spans().is_empty());
+    scope.include_trace(suite.suite_id);
+    suite.close();
+    assert_eq!(scope.get_finished_spans().len(), 1);
+    drop(scope);
+    module.close();
+    session.close(0);
+}
+
+#[test]
+fn concurrent_sessions() {
+    use crate::tags;
+
+    // Only keeps away the tests asserting on all the spans, the sessions below don't take it
+    let _lock = session_lock();
+    let barrier = Barrier::new(2);
+    let session_ids = Mutex::new(HashSet::new());
+
+    std::thread::scope(|threads| {
+        for (index, name) in ["concurrent-a", "concurrent-b"].into_iter().enumerate() {
+            let (barrier, session_ids) = (&barrier, &session_ids);
+            threads.spawn(move || {
+                let scope = MockTracer::scoped();
+                let session = TestSession::init_mock();
+                session_ids.lock().unwrap().insert(session.session_id);
+                let module = session.create_module(format!("{}-module", name), "Framework Name", "Framework Version");
+                let suite = module.create_test_suite(format!("{}-suite", name));
+                barrier.wait();
+
+                // The reset of one test must not remove the spans of the other
+                suite.create_test(format!("{}-first", name)).close(TestStatus::Pass);
+                barrier.wait();
+                if index == 0 {
+                    assert!(MockTracer::reset());
+                }
+                barrier.wait();
+                let first = scope.query().with_tag("test.name", format!("{}-first", name)).len();
+                assert_eq!(first, index);
+                assert!(!MockTracer::get_finished_spans().iter().any(|span| span.string_tags.get("test.name").is_some_and(|test| test.ends_with("-first"))));
+
+                // The first session closed doesn't shut the library down under the other one
+                if index == 0 {
+                    session.close(1);
+                    barrier.wait();
+                } else {
+                    barrier.wait();
+                    let test = suite.create_test(format!("{}-last", name));
+                    assert_ne!(test.test_id, 0);
+                    test.close(TestStatus::Pass);
+                    suite.close();
+                    module.close();
+                    assert_eq!(scope.query().with_tag("test.name", format!("{}-last", name)).len(), 1);
+                    assert_eq!(scope.query_open().by_operation("rust.test_session").len(), 1);
+                    session.close(0);
+                }
+            });
+        }
+    });
+
+    // Both handles shared one session, closed with the exit code of the first one
+    let session_ids = session_ids.into_inner().unwrap();
+    assert_eq!(session_ids.len(), 1);
+    let session_id = *session_ids.iter().next().unwrap();
+    let scope = MockTracer::scoped();
+    scope.include_trace(session_id);
+    let session_span = scope.get_finished_spans().into_iter().find(|span| span.span_id == session_id).unwrap();
+    assert_eq!(session_span.string_tags.get(tags::TEST_STATUS).map(String::as_str), Some(tags::TEST_STATUS_FAIL));
+    drop(scope);
+
+    // The next session initializes the library again
+    let session = TestSession::init_mock();
+    assert_ne!(session.session_id, session_id);
+    session.close(0);
+}
+
+#[test]
+fn wait_for_spans_and_leaks() {
+    use crate::mock_tracer::{LeakPolicy, LeakReport};
+    use crate::tags;
+
+    let _lock = session_lock();
+    let session = TestSession::init_mock();
+    assert_eq!(session.get_leak_policy(), LeakPolicy::Ignore);
+    session.set_leak_policy(LeakPolicy::Fail);
+    let scope = MockTracer::scoped();
+    let module = session.create_module("leak-module", "Framework Name", "Framework Version");
+    let suite = module.create_test_suite("leak-suite");
+    let test = suite.create_test("leak-test");
+
+    let span = Span::create(test.test_id, "leak-async", "", "", "custom");
+    let background = std::thread::spawn(move || {
+        sleep(Duration::from_millis(50));
+        span.close();
+    });
+    assert!(scope.wait_for_finished(|span| span.operation_name == "leak-async", Duration::ZERO).is_none());
+    let finished = scope.wait_for_finished(|span| span.operation_name == "leak-async", Duration::from_secs(5)).unwrap();
+    assert_eq!(finished.parent_span_id, test.test_id);
+    background.join().unwrap();
+    assert!(MockTracer::wait_for_finished(|span| span.operation_name == "leak-never", Duration::from_millis(20)).is_none());
+
+    let leaked = Span::create(test.test_id, "leak-span", "", "", "custom");
+    test.close(TestStatus::Pass);
+    let report = scope.leak_report();
+    let leaked_span = report.spans().iter().find(|span| span.span_id == leaked.span_id).unwrap();
+    assert_eq!(leaked_span.operation_name, "leak-span");
+    assert_eq!(leaked_span.test_name.as_deref(), Some("leak-test"));
+    assert!(report.spans().iter().any(|span| span.span_id == suite.suite_id && span.test_name.is_none()));
+    assert!(report.to_string().contains(&format!("  - `leak-span` ({}) in test `leak-test`, open for ", leaked.span_id)));
+    assert!(LeakReport::from_spans(vec![], vec![]).is_empty());
+
+    suite.close();
+    module.close();
+    // The leaked span fails the session
+    scope.include_trace(session.session_id);
+    session.close(0);
+    let session_span = scope.get_finished_spans().into_iter().find(|span| span.span_id == session.session_id).unwrap();
+    assert_eq!(session_span.string_tags.get(tags::TEST_STATUS).map(String::as_str), Some(tags::TEST_STATUS_FAIL));
+}
+
+#[test]
+fn mock_span_accessors() {
+    use crate::mock_tracer::{SpanError, SpanKind};
+
+    let _lock = session_lock();
+    let session = TestSession::init_mock();
+    let scope = MockTracer::scoped();
+    let module = session.create_module("accessors-module", "Framework Name", "Framework Version");
+    let suite = module.create_test_suite("accessors-suite");
+    let failed = suite.create_test("accessors-failed");
+    failed.set_error_info("MyError", "it failed", "at src/lib.rs:1");
+    let span = Span::create(failed.test_id, "accessors-span", "", "", "custom");
+    span.close();
+    failed.close(TestStatus::Fail);
+    let skipped = suite.create_test("accessors-skipped");
+    skipped.close_with_skip_reason("not today");
+    suite.close();
+    module.close();
+
+    let spans = scope.get_finished_spans();
+    let find = |span_id: u64| spans.iter().find(|span| span.span_id == span_id).unwrap();
+    assert_eq!(find(module.module_id).kind(), SpanKind::Module);
+    assert_eq!(find(suite.suite_id).kind(), SpanKind::Suite);
+    assert_eq!(find(span.span_id).kind(), SpanKind::Custom);
+    assert_eq!(find(span.span_id).test_status(), None);
+    assert!(find(span.span_id).duration().is_some());
+
+    let failed_span = find(failed.test_id);
+    assert_eq!(failed_span.kind(), SpanKind::Test);
+    assert_eq!(failed_span.test_name(), Some("accessors-failed"));
+    assert_eq!(failed_span.test_suite(), Some("accessors-suite"));
+    assert_eq!(failed_span.test_status(), Some(TestStatus::Fail));
+    assert_eq!(
+        failed_span.error(),
+        Some(SpanError { error_type: "MyError".to_string(), message: "it failed".to_string(), stacktrace: "at src/lib.rs:1".to_string() })
+    );
+    let skipped_span = find(skipped.test_id);
+    assert_eq!(skipped_span.test_status(), Some(TestStatus::Skip));
+    assert_eq!(skipped_span.skip_reason(), Some("not today"));
+    assert_eq!(skipped_span.error(), None);
+
+    let session_span = MockTracer::get_open_spans().into_iter().find(|span| span.span_id == session.session_id).unwrap();
+    assert_eq!(session_span.kind(), SpanKind::Session);
+    assert_eq!(session_span.duration(), None);
+    drop(scope);
+    session.close(0);
+}
+
+#[test]
+fn typed_tag_setters() {
+    use crate::tags::{self, RetryReason, TagSetter};
+
+    let _lock = session_lock();
+    let session = TestSession::init_mock();
+    let scope = MockTracer::scoped();
+    let module = session.create_module("tags-module", "Framework Name", "Framework Version");
+    let suite = module.create_test_suite("tags-suite");
+    assert!(suite.set_unskippable(true));
+    let test = suite.create_test("tags-test");
+    assert!(test.set_codeowners(&["@org/team", "user \"quoted\""]));
+    assert!(test.set_retry(RetryReason::EarlyFlakeDetection));
+    assert!(test.set_git_branch("main"));
+    assert!(TagSetter::set_error(&test, "AssertionError", "left != right", "at src/lib.rs:3"));
+    let span = Span::create(test.test_id, "tags-span", "", "", "custom");
+    assert!(span.set_is_retry(false) && span.set_retry_reason(RetryReason::AttemptToFix));
+    span.close();
+    assert!(test.set_bool_tag("custom.flag", false));
+    test.close(TestStatus::Pass);
+    suite.close();
+    module.close();
+
+    let spans = scope.query();
+    let test_span = spans.clone().with_tag(tags::TEST_NAME, "tags-test").first().cloned().unwrap();
+    test_span
+        .assert_tag_eq(tags::TEST_CODEOWNERS, r#"["@org/team","user \"quoted\""]"#)
+        .assert_tag_eq(tags::TEST_IS_RETRY, "true")
+        .assert_tag_eq(tags::TEST_RETRY_REASON, "early_flake_detection")
+        .assert_tag_eq(tags::GIT_BRANCH, "main")
+        .assert_tag_eq("custom.flag", "false")
+        .assert_tag_eq(tags::ERROR_TYPE, "AssertionError")
+        .assert_tag_eq(tags::ERROR_MESSAGE, "left != right")
+        .assert_tag_eq(tags::ERROR_STACK, "at src/lib.rs:3");
+    spans
+        .clone()
+        .by_operation("tags-span")
+        .first()
+        .unwrap()
+        .assert_tag_eq(tags::TEST_IS_RETRY, "false")
+        .assert_tag_eq(tags::TEST_RETRY_REASON, tags::RETRY_REASON_ATTEMPT_TO_FIX);
+    assert_eq!(spans.with_tag(tags::TEST_SUITE, "tags-suite").with_tag(tags::TEST_ITR_UNSKIPPABLE, "true").len(), 1);
+    drop(scope);
+    session.close(0);
+}
+
+#[test]
+fn naming_strategies() {
+    use crate::naming::{ModuleNaming, NamingStrategy, SuiteNaming, TestNames, TestTarget};
+
+    let strategy = NamingStrategy::new("my-crate");
+    assert_eq!(
+        strategy.names(&TestTarget::Unit, "my_crate::parser::tests", "it_works"),
+        TestNames {
+            module: "my_crate".to_string(),
+            suite: "my_crate::parser::tests".to_string(),
+            test: "it_works".to_string(),
+        }
+    );
+    assert_eq!(strategy.module_name(&TestTarget::Integration("api".to_string())), "my_crate/tests/api");
+    assert_eq!(strategy.module_name(&TestTarget::Doc), "my_crate/doc");
+    assert_eq!(strategy.module_name(&TestTarget::Bench("parser".to_string())), "my_crate/benches/parser");
+    // The module path of a libtest name goes to the suite, tests named the same in other modules don't collide
+    let names = strategy.names(&TestTarget::Unit, "my_crate", "parser::tests::it_works");
+    assert_eq!((names.suite.as_str(), names.test.as_str()), ("my_crate::parser::tests", "it_works"));
+    assert_ne!(strategy.names(&TestTarget::Unit, "my_crate", "lexer::tests::it_works"), names);
+    assert_eq!(strategy.names(&TestTarget::Unit, "my_crate::parser::tests", "parser::tests::it_works"), names);
+    assert_eq!(strategy.names(&TestTarget::Unit, "", "parser::tests::it_works"), names);
+
+    let strategy = NamingStrategy::new("my_crate")
+        .with_integration_tests(ModuleNaming::Crate)
+        .with_benches(ModuleNaming::Crate)
+        .with_suites(SuiteNaming::RelativeModulePath);
+    let names = strategy.names(&TestTarget::Integration("api".to_string()), "api::users", "creates_user");
+    assert_eq!((names.module.as_str(), names.suite.as_str()), ("my_crate", "users"));
+    let names = strategy.names(&TestTarget::Integration("api".to_string()), "api", "users::creates_user");
+    assert_eq!((names.suite.as_str(), names.test.as_str()), ("users", "creates_user"));
+    assert_eq!(strategy.suite_name("api"), "api");
+    assert_eq!(strategy.suite_name(""), "my_crate");
+    assert_eq!(strategy.module_name(&TestTarget::Bench("parser".to_string())), "my_crate");
+    assert_eq!(strategy.module_name(&TestTarget::Doc), "my_crate/doc");
+}
+
+#[test]
+fn doc_test_names() {
+    use crate::naming::{DocTestName, NamingStrategy};
+    use crate::paths::OutsideRepositoryPolicy;
+
+    let _lock = session_lock();
+    let doc_test = DocTestName::parse("src/parser.rs - parser::Parser::parse (line 42)").unwrap();
+    assert_eq!(
+        doc_test,
+        DocTestName { file: "src/parser.rs".to_string(), item_path: "parser::Parser::parse".to_string(), line: 42 }
+    );
+    let crate_docs = DocTestName::parse("src/lib.rs - (line 3)").unwrap();
+    assert_eq!((crate_docs.item_path.as_str(), crate_docs.line), ("", 3));
+    assert_eq!(DocTestName::parse("src/lib.rs - add (line 7) - compile fail").unwrap().item_path, "add");
+    assert!(DocTestName::parse("parser::tests::it_works").is_none());
+    assert!(DocTestName::parse("src/lib.rs - add (line x)").is_none());
+
+    let strategy = NamingStrategy::new("my-crate");
+    let names = doc_test.names(&strategy);
+    assert_eq!(
+        (names.module.as_str(), names.suite.as_str(), names.test.as_str()),
+        ("my_crate/doc", "my_crate::parser::Parser", "parse (line 42)")
+    );
+    let names = crate_docs.names(&strategy);
+    assert_eq!((names.suite.as_str(), names.test.as_str()), ("my_crate", "my_crate (line 3)"));
+
+    let session = TestSession::init_mock();
+    let module = session.create_doc_test_module(&strategy);
+    let suite = module.create_test_suite(&doc_test.names(&strategy).suite);
+    let test = suite.create_doc_test(&strategy, &doc_test);
+    test.close(TestStatus::Pass);
+    let span = MockTracer::get_finished_spans().into_iter().find(|span| span.span_id == test.test_id).unwrap();
+    span.assert_tag_eq("test.name", "parse (line 42)")
+        .assert_tag_eq("test.source.file", "src/parser.rs")
+        .assert_tag_eq("test.source.start", "42");
+    assert_eq!(span.tag("test.parameters"), None);
+
+    // Other examples of the item are other tests of the suite, their line is kept when the file is rejected
+    let other = DocTestName::parse("src/parser.rs - parser::Parser::parse (line 58)").unwrap();
+    assert_eq!(other.names(&strategy).suite, doc_test.names(&strategy).suite);
+    assert_ne!(other.names(&strategy).test, doc_test.names(&strategy).test);
+    session.set_path_normalizer(Some(PathNormalizer::new("/repo", "/elsewhere").with_policy(OutsideRepositoryPolicy::Reject)));
+    let test = suite.create_doc_test(&strategy, &other);
+    test.close(TestStatus::Pass);
+    let span = MockTracer::get_finished_spans().into_iter().find(|span| span.span_id == test.test_id).unwrap();
+    span.assert_tag_eq("test.name", "parse (line 58)").assert_tag_eq("test.source.start", "58");
+    assert_eq!(span.tag("test.source.file"), None);
+    suite.close();
+    module.close();
+    session.close(0);
+}
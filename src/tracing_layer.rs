@@ -0,0 +1,167 @@
+use crate::test_optimization::Span;
+use std::fmt;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+/********************************
+    Tracing layer
+*********************************/
+
+// Maps `tracing` spans to `Span`s parented under their parent `tracing` span,
+// or under the current test or span (see `Test::enter` and `Span::enter`) for the root ones.
+// Spans created outside of a test are ignored.
+#[derive(Debug, Clone)]
+pub struct TestOptimizationLayer {
+    service_name: String,
+    span_type: String,
+}
+
+impl Default for TestOptimizationLayer {
+    fn default() -> Self {
+        Self { service_name: String::new(), span_type: String::from("custom") }
+    }
+}
+
+impl TestOptimizationLayer {
+    #[allow(dead_code)]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[allow(dead_code)]
+    pub fn with_service_name(mut self, service_name: impl Into<String>) -> Self {
+        self.service_name = service_name.into();
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn with_span_type(mut self, span_type: impl Into<String>) -> Self {
+        self.span_type = span_type.into();
+        self
+    }
+}
+
+// Span created for a `tracing` span, stored in the span extensions
+struct TracedSpan(Span);
+
+impl<S> Layer<S> for TestOptimizationLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let Some(span_ref) = ctx.span(id) else {
+            return;
+        };
+        let metadata = attrs.metadata();
+        let parent = span_ref.parent().and_then(|parent| parent.extensions().get::<TracedSpan>().map(|traced| traced.0.clone()));
+        let span = match parent {
+            Some(parent) => parent.create_child(metadata.name(), &self.service_name, metadata.name(), &self.span_type),
+            None => match Span::start_in_current(metadata.name(), &self.service_name, metadata.name(), &self.span_type) {
+                Some(span) => span,
+                None => return,
+            },
+        };
+        if let Some(module_path) = metadata.module_path() {
+            span.set_string_tag("code.namespace", module_path);
+        }
+        attrs.record(&mut TagVisitor(&span));
+        span_ref.extensions_mut().insert(TracedSpan(span));
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span_ref) = ctx.span(id) {
+            if let Some(traced) = span_ref.extensions().get::<TracedSpan>() {
+                values.record(&mut TagVisitor(&traced.0));
+            }
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        if *event.metadata().level() != Level::ERROR {
+            return;
+        }
+        let Some(span_ref) = ctx.event_span(event) else {
+            return;
+        };
+        let extensions = span_ref.extensions();
+        let Some(traced) = extensions.get::<TracedSpan>() else {
+            return;
+        };
+        let mut error = ErrorVisitor::default();
+        event.record(&mut error);
+        traced.0.set_error_info(
+            error.error_type.unwrap_or_else(|| event.metadata().target().to_string()),
+            error.message,
+            error.stacktrace,
+        );
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        if let Some(span_ref) = ctx.span(&id) {
+            if let Some(traced) = span_ref.extensions_mut().remove::<TracedSpan>() {
+                traced.0.close();
+            }
+        }
+    }
+}
+
+// Records the fields as tags: numbers as number tags and everything else as string tags
+struct TagVisitor<'a>(&'a Span);
+
+impl Visit for TagVisitor<'_> {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.set_number_tag(field.name(), value);
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.set_number_tag(field.name(), value as f64);
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.set_number_tag(field.name(), value as f64);
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.set_string_tag(field.name(), value.to_string());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.set_string_tag(field.name(), value);
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0.set_string_tag(field.name(), format!("{:?}", value));
+    }
+}
+
+// Collects the error info from the `message`, `error.type` and `error.stack` fields of an event
+#[derive(Default)]
+struct ErrorVisitor {
+    error_type: Option<String>,
+    message: String,
+    stacktrace: String,
+}
+
+impl Visit for ErrorVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "error.type" => self.error_type = Some(value.to_string()),
+            "error.stack" => self.stacktrace = value.to_string(),
+            "message" | "error.message" => self.message = value.to_string(),
+            _ => {}
+        }
+    }
+
+    fn record_error(&mut self, field: &Field, value: &(dyn std::error::Error + 'static)) {
+        if self.message.is_empty() || field.name() == "error" {
+            self.message = value.to_string();
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.record_str(field, &format!("{:?}", value));
+    }
+}